@@ -0,0 +1,149 @@
+use key::{to_hex, from_hex};
+use ring::{digest, pbkdf2};
+use ring::error::Unspecified;
+use ring::rand::{SystemRandom, SecureRandom};
+use std::str::FromStr;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Reviewer,
+    Editor,
+    Superadmin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Read, // Listing entries, files, categories and tags
+    Review, // Committing or discarding stashed changes
+    Edit, // Stashing changes
+    Upload, // Uploading files
+    DeleteFiles, // Deleting uploaded files
+    Delete, // Deleting and restoring entries
+    Purge, // Removing deleted entries and their files for good
+    Taxonomy, // Managing categories and tags
+    GenKey, // Generating club keys
+    Accounts, // Managing administrative accounts
+    Audit, // Reading the audit log
+    Maintenance, // Collecting orphaned files
+}
+
+impl Role {
+    pub fn permits(&self, permission: Permission) -> bool {
+        match *self {
+            Role::Superadmin => true,
+            Role::Editor => permission != Permission::GenKey
+                && permission != Permission::Purge
+                && permission != Permission::Accounts
+                && permission != Permission::Audit,
+            Role::Reviewer =>
+                permission == Permission::Read || permission == Permission::Review,
+            Role::Viewer => permission == Permission::Read,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Role, ()> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "reviewer" => Ok(Role::Reviewer),
+            "editor" => Ok(Role::Editor),
+            "superadmin" => Ok(Role::Superadmin),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Account {
+    name: String,
+    role: Role,
+    salt: String, // Hex
+    hash: String, // Hex, PBKDF2-HMAC-SHA256 of the password
+}
+
+impl Account {
+    pub fn create(name: String, password: &str, role: Role) -> Result<Account, Unspecified> {
+        let mut account = Account {
+            name,
+            role,
+            salt: String::new(),
+            hash: String::new(),
+        };
+        account.set_password(password)?;
+        Ok(account)
+    }
+
+    // Stands in for unknown names, so that looking them up costs as much as a wrong password.
+    // Verifying against it is never relied on
+    pub fn dummy() -> Account {
+        Account {
+            name: String::new(),
+            role: Role::Viewer,
+            salt: "00".repeat(SALT_LEN),
+            hash: "00".repeat(digest::SHA256_OUTPUT_LEN),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    pub fn set_password(&mut self, password: &str) -> Result<(), Unspecified> {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt)?;
+
+        let mut hash = [0u8; digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(&digest::SHA256, PBKDF2_ITERATIONS, &salt, password.as_bytes(), &mut hash);
+
+        self.salt = to_hex(&salt);
+        self.hash = to_hex(&hash);
+        Ok(())
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        match (from_hex(&self.salt), from_hex(&self.hash)) {
+            (Some(salt), Some(hash)) => pbkdf2::verify(
+                &digest::SHA256,
+                PBKDF2_ITERATIONS,
+                &salt,
+                password.as_bytes(),
+                &hash).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_passwords() {
+        let account = Account::create("alice".to_owned(), "correct horse", Role::Editor).unwrap();
+        assert!(account.verify("correct horse"));
+        assert!(!account.verify("correct horse "));
+        assert!(!account.verify(""));
+    }
+
+    #[test]
+    fn dummy_rejects_passwords() {
+        assert!(!Account::dummy().verify(""));
+        assert!(!Account::dummy().verify("password"));
+    }
+}
@@ -1,24 +1,192 @@
+use account::{Account, Permission, Role};
 use config::Config;
-use key;
-use serde::Serializer;
+use date::Date;
+use files;
+use files::{FileName, Uploads};
+use key::{Claims, KeyRing, Scope};
+use mail;
+use ratelimit;
+use ratelimit::Limiter;
+use ring::constant_time;
 use serde_json::Value;
 use serde_json;
-use ring::error::Unspecified;
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::*;
-use std::io::Write;
-use std::path::Path;
+use std::error::Error;
 use std::sync::*;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std;
-use store::{Store, Entry, PullEntry};
-use uuid::Uuid;
-use ws::{Sender, Handshake, Message, Frame, CloseCode};
+use store::{Store, Entry, PullEntry, StoreError, Category, AuditRecord, Author};
+use ws::{Sender, Handshake, Message, CloseCode};
 use ws;
 use ws::util::Token;
 
+// Maximum number of audit records in one reply
+const AUDIT_PAGE: usize = 200;
+const PREVIEW_VALIDITY: u64 = 24 * 3600; // s
+// Abandoned uploads are looked for this many times per session_timeout
+const PRUNES_PER_TIMEOUT: u64 = 10;
+
 lazy_static! {
-    static ref SENDERS: RwLock<HashMap<Token, Sender>> = RwLock::new(HashMap::new());
+    // Authenticated connections, with the entry each is limited to
+    static ref SENDERS: RwLock<HashMap<Token, (Sender, Option<i32>)>> = RwLock::new(HashMap::new());
+    static ref LIMITER: Limiter = Limiter::new();
+    static ref UPLOADS: Uploads = Uploads::new();
+    // When orphaned files were last collected, and abandoned uploads pruned
+    static ref LAST_GC: Mutex<Option<Instant>> = Mutex::new(None);
+    static ref LAST_PRUNE: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+// Dates of a submitted entry must be YYYY-MM-DD, disbandment may be absent
+fn check_dates(payload: &Value) -> Result<(), StoreError> {
+    match payload.get("creation").and_then(Value::as_str) {
+        Some(creation) => creation.parse::<Date>()?,
+        None => return Err(StoreError::InvalidDate),
+    };
+
+    match payload.get("disbandment") {
+        None | Some(Value::Null) => Ok(()),
+        Some(Value::String(disbandment)) => disbandment.parse::<Date>().map(|_| ()).map_err(StoreError::from),
+        Some(_) => Err(StoreError::InvalidDate),
+    }
+}
+
+// Permission required by each command
+fn permission_of(cmd: &str) -> Option<Permission> {
+    match cmd {
+        "list" | "len" | "files" | "categories" | "tags" | "vocabulary" => Some(Permission::Read),
+        "put" | "reorderFiles" => Some(Permission::Edit),
+        "commit" | "discard" => Some(Permission::Review),
+        "del" | "deleted" | "restore" => Some(Permission::Delete),
+        "purge" => Some(Permission::Purge),
+        "uploadStart" | "uploadChunk" | "uploadStatus" | "uploadFinish" | "uploadCancel" =>
+            Some(Permission::Upload),
+        "deleteFile" => Some(Permission::DeleteFiles),
+        "createCategory" | "updateCategory" | "mergeCategory"
+            | "renameTag" | "mergeTags" | "addVocabulary" | "removeVocabulary" => Some(Permission::Taxonomy),
+        "genKey" | "sendKey" | "keys" | "deliveries" | "revokeKey" => Some(Permission::GenKey),
+        "accounts" | "putAccount" | "delAccount" => Some(Permission::Accounts),
+        "audit" => Some(Permission::Audit),
+        "orphans" | "collectOrphans" => Some(Permission::Maintenance),
+        _ => None,
+    }
+}
+
+// Whether a periodic task last run at `last` is due again, in which case it is marked as run now
+fn is_due(last: &Mutex<Option<Instant>>, interval: u64) -> bool {
+    let mut last = last.lock().unwrap();
+    let due = last.map_or(true, |t| t.elapsed() >= Duration::from_secs(interval));
+    if due {
+        *last = Some(Instant::now());
+    }
+    due
+}
+
+// Removes abandoned upload sessions, a few times within every session_timeout
+pub fn prune_uploads(config: &Config) {
+    if is_due(&LAST_PRUNE, config.upload.session_timeout / PRUNES_PER_TIMEOUT) {
+        UPLOADS.prune(config.upload.session_timeout);
+    }
+}
+
+// Collects orphaned files once every gc_interval
+pub fn collect_orphans(store: &RwLock<Store>, config: &Config) {
+    if !is_due(&LAST_GC, config.upload.gc_interval) {
+        return;
+    }
+
+    if let Err(e) = files::collect(&mut store.write().unwrap(), config.upload.orphan_grace) {
+        println!("Collecting orphaned files failed: {}", e.description());
+    }
+}
+
+// Every change of an entry is announced with one of the events below:
+// {"cmd":"update","id","payload"} once it is created, edited, stashed, committed, discarded or restored,
+// with the payload as in list, and {"cmd":"delete","id","purged"} once it is deleted or purged
+fn update_event(id: i32, payload: &Value) -> String {
+    json!({
+        "cmd": "update",
+        "id": id,
+        "payload": payload,
+    }).to_string()
+}
+
+fn delete_event(id: i32, purged: bool) -> String {
+    json!({
+        "cmd": "delete",
+        "id": id,
+        "purged": purged,
+    }).to_string()
+}
+
+// Payload of the update event of an entry
+fn pull_payload(s: &Store, id: i32) -> ws::Result<Value> {
+    serde_json::to_value(s.pull_fetch(id)).map_err(|e| err_to_wserr(e, "Serialization Failed"))
+}
+
+// Sends an event about an entry to connections allowed to see it, other than `skip`
+// Clubs only receive events of their own entry, as entries carry private fields like the contact
+fn broadcast_event(id: i32, event: &str, skip: Option<Token>) -> ws::Result<()> {
+    for (token, &(ref sender, limited)) in SENDERS.read().unwrap().iter() {
+        if Some(*token) != skip && limited.map_or(true, |l| l == id) {
+            sender.send(event)?;
+        }
+    }
+    Ok(())
+}
+
+// Target entry, label, expiry and scope of genKey and sendKey
+fn key_request(data: &Value) -> Option<(i32, Option<String>, Option<u64>, Scope)> {
+    let entry = data["target"].as_i64()? as i32;
+    let label = data["label"].as_str().map(str::to_owned);
+    let expiry = data["expiry"].as_u64();
+    let scope = match data["scope"] {
+        Value::Null => Scope::ALL,
+        ref s => serde_json::from_value(s.clone()).ok()?,
+    };
+    Some((entry, label, expiry, scope))
+}
+
+// Commands recorded in the audit log. Chunks and progress queries would only flood it
+fn is_audited(cmd: &str, permission: Permission) -> bool {
+    permission != Permission::Read
+        && cmd != "uploadChunk" && cmd != "uploadStatus" && cmd != "orphans" && cmd != "deleted"
+}
+
+// Entry and further detail an audited command acts on
+fn audit_target(data: &Value) -> (Option<i32>, Option<String>) {
+    let number = |v: &Value| v.as_i64().map(|i| i as i32);
+    let string = |v: &Value| v.as_str().map(str::to_owned);
+    match data["cmd"].as_str().unwrap_or("") {
+        "put" => (number(&data["payload"]["id"]), None),
+        "commit" | "discard" | "reorderFiles" => (number(&data["id"]), None),
+        "del" => (number(&data["target"]), None),
+        "restore" | "purge" => (number(&data["id"]), None),
+        "genKey" | "sendKey" => (number(&data["target"]), string(&data["label"])),
+        "uploadStart" => (number(&data["entry"]), string(&data["filename"])),
+        "uploadFinish" | "uploadCancel" => (None, string(&data["id"])),
+        "deleteFile" => {
+            let filename = string(&data["target"]);
+            let entry = filename.as_ref()
+                .and_then(|f| FileName::parse(f))
+                .map(|f| f.entry);
+            (entry, filename)
+        },
+        "revokeKey" => (number(&data["entry"]), data["id"].as_u64().map(|id| id.to_string())),
+        "createCategory" | "updateCategory" => (None, string(&data["payload"]["name"])),
+        "mergeCategory" | "renameTag" | "mergeTags" =>
+            (None, Some(format!("{} -> {}", data["from"], data["into"]))),
+        "addVocabulary" | "removeVocabulary" => (None, Some(data["tags"].to_string())),
+        "putAccount" | "delAccount" => (None, string(&data["name"])),
+        _ => (None, None),
+    }
+}
+
+// Authenticated party of a connection
+enum Principal {
+    Admin(String, Role), // Account name, empty for the master secret
+    Club(Claims),
 }
 
 fn err_to_wserr<T, I: Into<Cow<'static, str>>>(e: T, reason: I) -> ws::Error
@@ -30,17 +198,136 @@ pub struct Handler {
     sender: Sender,
     store: &'static RwLock<Store>,
     config: &'static Config,
-    uploading: Option<File>,
-    limited: Option<i32>,
+    keyring: &'static KeyRing,
+    chunk: Option<(String, u64)>, // Upload id and offset announced by uploadChunk, awaiting the data
+    principal: Option<Principal>,
+    addr: String, // Client address, for rate limiting
+    auditing: Option<AuditRecord>, // Record of the command being handled
+    outcome: RefCell<Option<Value>>, // First reply to the audited command, if the handler succeeds
 }
 
 impl Handler {
-    pub fn new(sender: Sender, store: &'static RwLock<Store>, config: &'static Config) -> Handler {
-        Handler { sender, store, config, uploading: None, limited: None, }
+    pub fn new(
+        sender: Sender,
+        store: &'static RwLock<Store>,
+        config: &'static Config,
+        keyring: &'static KeyRing) -> Handler {
+        Handler {
+            sender,
+            store,
+            config,
+            keyring,
+            chunk: None,
+            principal: None,
+            addr: String::new(),
+            auditing: None,
+            outcome: RefCell::new(None),
+        }
+    }
+
+    // Sends to this connection, keeping the outcome of an audited command
+    fn reply<M: Into<Message>>(&self, msg: M) -> ws::Result<()> {
+        let msg = msg.into();
+        if self.auditing.is_some() {
+            let mut outcome = self.outcome.borrow_mut();
+            if outcome.is_none() {
+                *outcome = Some(msg.as_text().ok()
+                    .and_then(|t| serde_json::from_str(t).ok())
+                    .unwrap_or(Value::Null));
+            }
+        }
+        self.sender.send(msg)
+    }
+
+    // Name of the principal in the audit log
+    fn actor(&self) -> Option<String> {
+        match self.principal {
+            None => None,
+            Some(Principal::Admin(ref name, _)) if name.is_empty() => Some("secret".to_owned()),
+            Some(Principal::Admin(ref name, _)) => Some(format!("account:{}", name)),
+            Some(Principal::Club(ref claims)) => Some(format!("key:{}", claims.id)),
+        }
+    }
+
+    // Entry the connection is limited to, if authenticated with a club key
+    fn limited(&self) -> Option<i32> {
+        match self.principal {
+            Some(Principal::Club(ref claims)) => Some(claims.entry),
+            _ => None,
+        }
+    }
+
+    fn permits(&self, permission: Permission) -> bool {
+        match self.principal {
+            None => false,
+            Some(Principal::Admin(_, role)) => role.permits(permission),
+            Some(Principal::Club(ref claims)) => match permission {
+                Permission::Read => true,
+                Permission::Edit => claims.scope.contains(Scope::EDIT),
+                Permission::Upload => claims.scope.contains(Scope::UPLOAD),
+                Permission::DeleteFiles => claims.scope.contains(Scope::DELETE_FILES),
+                _ => false,
+            },
+        }
+    }
+
+    // Accepts {"key"} for the master secret and club keys, or {"name", "password"} for accounts
+    fn authenticate(&self, data: &Value) -> Option<Principal> {
+        let is_secret = data["key"].as_str().map_or(false, |k| {
+            constant_time::verify_slices_are_equal(k.as_bytes(), self.config.secret.as_bytes()).is_ok()
+        });
+        if is_secret {
+            return Some(Principal::Admin(String::new(), Role::Superadmin));
+        }
+
+        if let (Some(name), Some(password)) = (data["name"].as_str(), data["password"].as_str()) {
+            // Cloned out, so that the store is not locked during the deliberately slow check.
+            // Unknown names are checked as well, so that timing does not tell which names exist
+            let account = {
+                let s = self.store.read().unwrap();
+                s.account(name)
+            };
+            let found = account.is_some();
+            let account = account.unwrap_or_else(Account::dummy);
+            if account.verify(password) && found {
+                return Some(Principal::Admin(name.to_owned(), account.role()));
+            }
+            return None;
+        }
+
+        self.try_decrypt_key(data["key"].as_str()?)
+            .and_then(|claims| if self.is_valid(&claims) { Some(claims) } else { None })
+            .map(Principal::Club)
+    }
+
+    // Reviewers see which key, or which administrator, submitted a change
+    fn author(&self, s: &Store) -> Option<Author> {
+        self.actor().map(|actor| Author {
+            actor,
+            label: match self.principal {
+                Some(Principal::Club(ref claims)) => s.key(claims.id).and_then(|k| k.label),
+                _ => None,
+            },
+        })
+    }
+
+    // Keys of deleted entries are refused until the entry is restored
+    fn is_valid(&self, claims: &Claims) -> bool {
+        let s = self.store.read().unwrap();
+        !claims.is_expired() && !s.is_revoked(claims) && s.admits_keys(claims.entry)
+    }
+
+    fn reply_err(&self, e: &StoreError) -> ws::Result<()> {
+        let s = json!({
+            "ok": 0,
+            "error": format!("{:?}", e),
+            "desc": e.description(),
+        }).to_string();
+        self.reply(s)
     }
 
     fn list(&self) -> ws::Result<()> {
-        let result = match self.limited {
+        let result = match self.limited() {
             None => self.store.read().unwrap().pull(),
             Some(id) => self.store.read().unwrap().pull_fetch(id)
                 .map_or_else(Vec::new, |e| {
@@ -53,201 +340,737 @@ impl Handler {
             Err(e) => return Err(err_to_wserr(e, "Serialization failed")),
             Ok(d) => d,
         };
-        self.sender.send(s)?;
+        self.reply(s)?;
         Ok(())
     }
 
     fn put(&self, payload: Value) -> ws::Result<()> {
+        // Entry tolerates legacy dates on deserialization, so new ones are checked here
+        if let Err(e) = check_dates(&payload) {
+            return self.reply_err(&e);
+        }
+
         let payload: Entry = match serde_json::from_value(payload) {
-            Err(e) => return Err(err_to_wserr(e, "Deserialization failed")),
+            Err(e) => {
+                // Malformed fields
+                let s = json!({
+                    "ok": 0,
+                    "error": "InvalidPayload",
+                    "desc": e.to_string(),
+                }).to_string();
+                return self.reply(s);
+            },
             Ok(d) => d,
         };
 
-        if self.limited.is_some()
-            && Some(payload.id()) != self.limited {
+        if self.limited().is_some()
+            && Some(payload.id()) != self.limited() {
 
             // Permission denied
-            self.sender.send("{\"ok\":0}")?;
-            return Ok(())
+            return self.reply_err(&StoreError::Denied);
         }
 
         let mut s = self.store.write().unwrap();
         let id = payload.id();
 
-        if let Err(e) = s.stash(payload, self.limited.is_some()) {
-            Err(err_to_wserr(e, "Storage failure"))
-        } else {
-            let payload = match serde_json::to_value(s.pull_fetch(id)) {
-                Err(e) => return Err(err_to_wserr(e, "Serialization Failed")),
-                Ok(p) => p,
-            };
+        if self.limited().is_some() && self.config.controlled_tags {
+            if let Err(e) = s.check_vocabulary(&payload) {
+                return self.reply_err(&e);
+            }
+        }
 
+        let author = self.author(&s);
+        if let Err(e) = s.stash(payload, self.limited().is_some(), author) {
+            self.reply_err(&e)
+        } else {
+            let payload = pull_payload(&s, id)?;
             std::mem::drop(s);
-            let content = json!({
-                "cmd": "update",
-                "id": id,
-                "payload": payload,
-            }).to_string();
 
             let pong = json!({
                 "ok": 1,
                 "payload": payload,
             }).to_string();
 
-            for (k, v) in SENDERS.read().unwrap().iter() {
-                if k != &self.sender.token() {
-                    v.send(content.clone())?;
-                }
-            }
-
-            self.sender.send(pong)
+            broadcast_event(id, &update_event(id, &payload), Some(self.sender.token()))?;
+            self.reply(pong)
         }
     }
 
     fn del(&self, target: Value) -> ws::Result<()> {
-        if self.limited.is_some() {
-            self.sender.send("{\"ok\":0}")?; // Denied
-            return Ok(())
+        let id = match target.as_i64() {
+            Some(id) => id as i32,
+            None => return self.reply("{\"ok\":0}"),
+        };
+
+        if let Err(e) = self.store.write().unwrap().del(id) {
+            return self.reply_err(&e);
         }
 
-        if let Value::Number(n) = target {
-            if let Some(id) = n.as_i64() {
-                if self.store.write().unwrap().del(id as i32).is_ok() {
-                    self.sender.send("{\"ok\":1}")?;
-                    return Ok(())
-                }
+        // The requesting client has already removed it
+        broadcast_event(id, &delete_event(id, false), Some(self.sender.token()))?;
+        self.reply("{\"ok\":1}")
+    }
+
+    fn deleted(&self) -> ws::Result<()> {
+        let s = json!({
+            "ok": 1,
+            "entries": self.store.read().unwrap().deleted(),
+        }).to_string();
+        self.reply(s)
+    }
+
+    fn restore(&self, data: &Value) -> ws::Result<()> {
+        let id = match data["id"].as_i64() {
+            Some(id) => id as i32,
+            None => return self.reply("{\"ok\":0}"),
+        };
+
+        let mut s = self.store.write().unwrap();
+        if let Err(e) = s.restore(id) {
+            return self.reply_err(&e);
+        }
+
+        self.broadcast_updates(&s, &[id])?;
+        self.reply("{\"ok\":1}")
+    }
+
+    fn purge(&self, data: &Value) -> ws::Result<()> {
+        let id = match data["id"].as_i64() {
+            Some(id) => id as i32,
+            None => return self.reply("{\"ok\":0}"),
+        };
+
+        // Held until the blobs are gone, so that no upload can record one of them meanwhile
+        let mut s = self.store.write().unwrap();
+        let unused = match s.purge(id) {
+            Err(e) => return self.reply_err(&e),
+            Ok(u) => u,
+        };
+        broadcast_event(id, &delete_event(id, true), None)?;
+
+        // The purge is committed already, so every blob is tried before reporting a failure
+        let mut failed = None;
+        for sha256 in unused {
+            if let Err(e) = files::remove_blob(&sha256) {
+                println!("Removing blob {} failed: {}", sha256, e.description());
+                failed = Some(e);
             }
         }
-        self.sender.send("{\"ok\":0}")?;
-        // TODO: syncdown for del
-        Ok(())
+        std::mem::drop(s);
+
+        match failed {
+            Some(e) => self.reply_err(&e),
+            None => self.reply("{\"ok\":1}"),
+        }
     }
 
     fn files(&self, target: Value) -> ws::Result<()> {
-        let iter = match read_dir(Path::new("./static/store")) {
-            Err(e) => return Err(err_to_wserr(e, "Not privleged to read directory")),
-            Ok(i) => i,
+        let entry = target.as_i64().map(|i| i as i32);
+        if self.limited().is_some() && self.limited() != entry {
+            return self.reply("{\"ok\":0}");
+        }
+
+        // Reflects pending changes, so that clubs see their own arrangement before review
+        let s = self.store.read().unwrap();
+        let latest = entry.and_then(|id| s.latest(id));
+        let list = files::arrange(s.files(entry), latest.as_ref());
+        std::mem::drop(s);
+
+        match serde_json::to_string(&list) {
+            Err(e) => Err(err_to_wserr(e, "Serialization failed")),
+            Ok(s) => self.reply(s),
+        }
+    }
+
+    fn reorder_files(&self, data: &Value) -> ws::Result<()> {
+        let id = match data["id"].as_i64() {
+            Some(id) => id as i32,
+            None => return self.reply("{\"ok\":0}"),
+        };
+
+        if self.limited().is_some() && self.limited() != Some(id) {
+            return self.reply_err(&StoreError::Denied);
+        }
+
+        let files: Vec<String> = match serde_json::from_value(data["files"].clone()) {
+            Ok(f) => f,
+            Err(_) => return self.reply("{\"ok\":0}"),
+        };
+
+        let captions = match data["captions"] {
+            Value::Null => None,
+            ref c => match serde_json::from_value(c.clone()) {
+                Ok(c) => Some(c),
+                Err(_) => return self.reply("{\"ok\":0}"),
+            },
         };
 
-        let mut entry = None;
-        if let Value::Number(n) = target {
-            if let Some(i) = n.as_i64() {
-                entry = Some(i as i32)
+        let mut s = self.store.write().unwrap();
+        let author = self.author(&s);
+        if let Err(e) = s.reorder_files(id, files, captions, self.limited().is_some(), author) {
+            return self.reply_err(&e);
+        }
+
+        let payload = pull_payload(&s, id)?;
+        std::mem::drop(s);
+
+        broadcast_event(id, &update_event(id, &payload), Some(self.sender.token()))?;
+        self.reply(json!({
+            "ok": 1,
+            "payload": payload,
+        }).to_string())
+    }
+
+    fn start_upload(&self, data: &Value) -> ws::Result<()> {
+        let request = (data["entry"].as_i64(), data["ext"].as_str(), data["size"].as_u64(), data["sha256"].as_str());
+        let upload = match request {
+            (Some(entry), Some(ext), Some(size), Some(sha256)) => files::NewUpload {
+                entry: entry as i32,
+                ext: ext.to_owned(),
+                size,
+                sha256: sha256.to_owned(),
+                original: data["filename"].as_str().unwrap_or("").to_owned(),
+                caption: data["caption"].as_str().filter(|c| !c.trim().is_empty()).map(str::to_owned),
+                uploader: self.actor(),
+            },
+            _ => return self.reply("{\"ok\":0}"),
+        };
+        let entry = upload.entry;
+
+        if self.limited().is_some() && self.limited() != Some(entry) {
+            return self.reply_err(&StoreError::Denied);
+        }
+
+        let used: u64 = self.store.read().unwrap().files(Some(entry)).iter().map(|f| f.size).sum();
+        match UPLOADS.start(upload, used, &self.config.upload) {
+            Err(e) => self.reply_err(&e),
+            Ok(id) => self.reply(json!({
+                "ok": 1,
+                "id": id,
+                "offset": 0,
+                "size": size,
+            }).to_string()),
+        }
+    }
+
+    fn upload_session(&mut self, data: &Value) -> ws::Result<()> {
+        let id = match data["id"].as_str() {
+            Some(id) => id,
+            None => return self.reply("{\"ok\":0}"),
+        };
+        let limited = self.limited();
+
+        if data["cmd"] == "uploadStatus" {
+            match UPLOADS.status(id, limited) {
+                Err(e) => self.reply_err(&e),
+                Ok((offset, size)) => self.reply(json!({
+                    "ok": 1,
+                    "id": id,
+                    "offset": offset,
+                    "size": size,
+                }).to_string()),
             }
+        } else if data["cmd"] == "uploadCancel" {
+            match UPLOADS.cancel(id, limited) {
+                Err(e) => self.reply_err(&e),
+                Ok(_) => self.reply("{\"ok\":1}"),
+            }
+        } else {
+            // Attaching modifies the entry, which club keys may only do when allowed to edit
+            let icon = data["icon"].as_bool().unwrap_or(false);
+            let attach = icon || data["attach"].as_bool().unwrap_or(false);
+            if attach && !self.permits(Permission::Edit) {
+                return self.reply_err(&StoreError::Denied);
+            }
+
+            let max_pixels = self.config.upload.max_pixels;
+            let name = match UPLOADS.finish(self.store, id, limited, max_pixels) {
+                Err(e) => return self.reply_err(&e),
+                Ok(record) => {
+                    files::render_later(vec![(record.sha256, record.mime)], max_pixels);
+                    record.name
+                },
+            };
+
+            // The stored name only exists once the upload is complete
+            if let Some(ref mut record) = self.auditing {
+                record.target = FileName::parse(&name).map(|f| f.entry);
+                record.detail = Some(name.clone());
+            }
+
+            if !attach {
+                return self.reply(json!({
+                    "ok": 1,
+                    "name": name,
+                }).to_string());
+            }
+
+            self.attach_file(&name, icon)
+        }
+    }
+
+    // Adds a finished upload to its entry through the stash, as put does
+    fn attach_file(&self, name: &str, icon: bool) -> ws::Result<()> {
+        let id = match FileName::parse(name) {
+            Some(f) => f.entry,
+            None => return self.reply_err(&StoreError::InvalidFileName),
+        };
+
+        let mut s = self.store.write().unwrap();
+        let author = self.author(&s);
+        if let Err(e) = s.attach_file(id, name, icon, self.limited().is_some(), author) {
+            // The file is kept, so that the client can still attach it with put
+            return self.reply(json!({
+                "ok": 0,
+                "error": format!("{:?}", e),
+                "desc": e.description(),
+                "name": name,
+            }).to_string());
+        }
+
+        let payload = pull_payload(&s, id)?;
+        std::mem::drop(s);
+
+        broadcast_event(id, &update_event(id, &payload), Some(self.sender.token()))?;
+        self.reply(json!({
+            "ok": 1,
+            "name": name,
+            "payload": payload,
+        }).to_string())
+    }
+
+    // Data of the chunk announced by uploadChunk
+    fn receive_chunk(&mut self, data: Vec<u8>) -> ws::Result<()> {
+        let (id, offset) = match self.chunk.take() {
+            Some(c) => c,
+            None => return self.reply("{\"ok\":0,\"error\":\"UnexpectedData\"}"),
+        };
+
+        match UPLOADS.write(&id, self.limited(), offset, &data) {
+            Ok(offset) => self.reply(json!({
+                "ok": 1,
+                "id": id,
+                "offset": offset,
+            }).to_string()),
+            Err(StoreError::InvalidOffset) => {
+                // Lets the client continue from what was actually received
+                let (current, _) = UPLOADS.status(&id, self.limited()).unwrap_or((0, 0));
+                let e = StoreError::InvalidOffset;
+                self.reply(json!({
+                    "ok": 0,
+                    "error": format!("{:?}", e),
+                    "desc": e.description(),
+                    "offset": current,
+                }).to_string())
+            },
+            Err(e) => self.reply_err(&e),
         }
+    }
+
+    fn orphans(&self, data: &Value) -> ws::Result<()> {
+        let grace = self.config.upload.orphan_grace;
+        let mut s = self.store.write().unwrap();
 
-        if self.limited.is_some() {
-            if self.limited != entry {
-                self.sender.send("{\"ok\":0}")?;
-                return Ok(())
+        if data["cmd"] == "collectOrphans" {
+            match files::collect(&mut s, grace) {
+                Err(e) => self.reply_err(&e),
+                Ok(removed) => self.reply(json!({
+                    "ok": 1,
+                    "removed": removed,
+                }).to_string()),
             }
+        } else {
+            self.reply(json!({
+                "ok": 1,
+                "grace": grace,
+                "orphans": s.orphans(),
+            }).to_string())
+        }
+    }
+
+    fn delete_file(&self, data: &Value) -> ws::Result<()> {
+        let filename = match data["target"].as_str() {
+            Some(s) => s,
+            None => return self.reply("{\"ok\":0}"),
+        };
+
+        // Clubs may only delete their own uploads
+        if !files::permits(filename, self.limited()) {
+            return self.reply_err(&StoreError::Denied);
         }
 
-        let filtered = iter.filter_map(|e| {
-            let e = match e {
-                Err(_) => return None,
-                Ok(e) => e,
+        let mut s = self.store.write().unwrap();
+        let unused = match s.forget_file(filename) {
+            Err(e) => return self.reply_err(&e),
+            Ok(u) => u,
+        };
+
+        // The content is kept while other files share it
+        if let Some(sha256) = unused {
+            if let Err(e) = files::remove_blob(&sha256) {
+                return self.reply_err(&e);
+            }
+        }
+
+        let affected = match s.detach_file(filename) {
+            Err(e) => return self.reply_err(&e),
+            Ok(a) => a,
+        };
+
+        self.broadcast_updates(&s, &affected)?;
+        self.reply("{\"ok\":1}")
+    }
+
+    fn edit_category(&self, data: &Value) -> ws::Result<()> {
+        let mut s = self.store.write().unwrap();
+        let result = if data["cmd"] == "mergeCategory" {
+            match (data["from"].as_i64(), data["into"].as_i64()) {
+                (Some(from), Some(into)) => s.merge_category(from as i32, into as i32),
+                _ => return self.reply("{\"ok\":0}"),
+            }
+        } else {
+            let category: Category = match serde_json::from_value(data["payload"].clone()) {
+                Err(_) => return self.reply("{\"ok\":0}"),
+                Ok(c) => c,
             };
 
-            let ft = e.file_type();
-            if ft.is_err() || !ft.unwrap().is_file() {
-                return None;
+            if data["cmd"] == "createCategory" {
+                s.create_category(category).map(|_| Vec::new())
+            } else {
+                s.update_category(category)
             }
+        };
 
-            e.path()
-                .file_name()
-                .filter(|s| *s != ".gitkeep")
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_string())
-                .map(|filename| (e.metadata().unwrap().modified().unwrap(), filename))
-        });
+        let affected = match result {
+            Err(e) => return self.reply_err(&e),
+            Ok(a) => a,
+        };
+
+        self.broadcast_updates(&s, &affected)?;
+
+        let s = json!({
+            "ok": 1,
+            "categories": s.categories(),
+        }).to_string();
+        self.reply(s)
+    }
 
-        let mut collected: Vec<_> = if let Some(id) = entry {
-            // Filter uploads by prefixes
-            let prefix = format!("{}.", id);
-            filtered.filter(|&(_, ref f)| f.starts_with(&prefix)).collect()
-        } else { filtered.collect() };
+    fn edit_tags(&self, data: &Value) -> ws::Result<()> {
+        let from: Vec<String> = if data["cmd"] == "renameTag" {
+            match data["from"] {
+                Value::String(ref s) => vec![s.clone()],
+                _ => return self.reply("{\"ok\":0}"),
+            }
+        } else {
+            match serde_json::from_value(data["from"].clone()) {
+                Err(_) => return self.reply("{\"ok\":0}"),
+                Ok(f) => f,
+            }
+        };
 
-        collected.sort_unstable_by(|a,b| b.cmp(a));
+        let into = match data["into"] {
+            Value::String(ref s) => s,
+            _ => return self.reply("{\"ok\":0}"),
+        };
 
-        let iter = collected.iter().map(|&(_, ref f)| f);
+        let mut s = self.store.write().unwrap();
+        let affected = match s.merge_tags(&from, into) {
+            Err(e) => return self.reply_err(&e),
+            Ok(a) => a,
+        };
 
-        let mut writer = Vec::with_capacity(128);
-        let mut ser = serde_json::ser::Serializer::new(&mut writer);
-        let result = ser.collect_seq(iter);
-        if let Err(e) = result {
-            Err(err_to_wserr(e, "Serialization failed"))
+        self.broadcast_updates(&s, &affected)?;
+
+        let s = json!({
+            "ok": 1,
+            "tags": s.tags(),
+        }).to_string();
+        self.reply(s)
+    }
+
+    fn edit_vocabulary(&self, data: &Value) -> ws::Result<()> {
+        let tags: Vec<String> = match serde_json::from_value(data["tags"].clone()) {
+            Err(_) => return self.reply("{\"ok\":0}"),
+            Ok(t) => t,
+        };
+
+        let mut s = self.store.write().unwrap();
+        if data["cmd"] == "addVocabulary" {
+            if let Err(e) = s.add_vocabulary(&tags) {
+                return self.reply_err(&e);
+            }
         } else {
-            // TODO: maybe there is arbitary files in the directory
-            self.sender.send(String::from_utf8(writer).unwrap())?;
-            Ok(())
+            s.remove_vocabulary(&tags);
         }
+
+        let s = json!({
+            "ok": 1,
+            "vocabulary": s.vocabulary(),
+        }).to_string();
+        self.reply(s)
     }
 
-    pub fn generate_key(&self, target: i32) -> Result<String, Unspecified> {
-        key::generate_key(target, self.config.secret.as_bytes())
+    fn edit_accounts(&self, data: &Value) -> ws::Result<()> {
+        let name = match data["name"] {
+            Value::String(ref s) => s.clone(),
+            _ => return self.reply("{\"ok\":0}"),
+        };
+
+        let result = if data["cmd"] == "delAccount" {
+            self.store.write().unwrap().del_account(&name)
+        } else {
+            let role: Role = match serde_json::from_value(data["role"].clone()) {
+                Err(_) => return self.reply("{\"ok\":0}"),
+                Ok(r) => r,
+            };
+
+            // Passwords are hashed without holding the store
+            let existing = self.store.read().unwrap().account(&name);
+            let password = data["password"].as_str();
+            let account = match (existing, password) {
+                (Some(mut account), password) => {
+                    account.set_role(role);
+                    match password.map(|p| account.set_password(p)) {
+                        Some(Err(_)) => return self.reply("{\"ok\":0}"),
+                        _ => account,
+                    }
+                },
+                (None, Some(password)) => match Account::create(name, password, role) {
+                    Err(_) => return self.reply("{\"ok\":0}"),
+                    Ok(a) => a,
+                },
+                (None, None) => return self.reply("{\"ok\":0}"), // New accounts need a password
+            };
+            self.store.write().unwrap().put_account(&account)
+        };
+
+        if let Err(e) = result {
+            return self.reply_err(&e);
+        }
+        self.send_accounts(&self.store.read().unwrap())
     }
 
-    pub fn try_decrypt_key(&self, key: &str) -> Option<i32> {
-        key::try_decrypt_key(key, self.config.secret.as_bytes())
+    fn send_accounts(&self, s: &Store) -> ws::Result<()> {
+        let accounts: Vec<Value> = s.accounts().iter().map(|a| json!({
+            "name": a.name(),
+            "role": a.role(),
+        })).collect();
+
+        let s = json!({
+            "ok": 1,
+            "accounts": accounts,
+        }).to_string();
+        self.reply(s)
     }
-}
 
-impl ws::Handler for Handler {
-    fn on_close(&mut self, code: CloseCode, reason: &str) {
-        // Closed
-        SENDERS
-            .write()
-            .unwrap()
-            .remove(&self.sender.token());
+    // Sends the current state of modified entries to all clients allowed to see them
+    fn broadcast_updates(&self, s: &Store, ids: &[i32]) -> ws::Result<()> {
+        for id in ids {
+            let payload = pull_payload(s, *id)?;
+            broadcast_event(*id, &update_event(*id, &payload), None)?;
+        }
+        Ok(())
     }
 
-    fn on_open(&mut self, shake: Handshake) -> ws::Result<()> {
+    fn generate_key(&self, data: &Value) -> ws::Result<()> {
+        let (entry, label, expiry, scope) = match key_request(data) {
+            Some(r) => r,
+            None => return self.reply("{\"ok\":0}"),
+        };
+
+        let issued = self.store.write().unwrap().issue_key(entry, label, expiry, scope, self.keyring.current_id());
+        let claims = match issued {
+            Err(e) => return self.reply_err(&e),
+            Ok(c) => c,
+        };
+
+        let key = match self.keyring.generate_key(&claims) {
+            Err(_) => return self.reply("{\"ok\":0}"),
+            Ok(s) => s,
+        };
+
+        let s = json!({
+            "ok": 1,
+            "key": key,
+            "claims": claims,
+        }).to_string();
+        self.reply(s)
+    }
+
+    // Delivers a new key to the contact of the entry, as a one-time link
+    fn send_key(&self, data: &Value) -> ws::Result<()> {
+        let config = match self.config.mail {
+            Some(ref c) => c,
+            None => return self.reply("{\"ok\":0,\"error\":\"MailNotConfigured\"}"),
+        };
+
+        let (entry, label, expiry, scope) = match key_request(data) {
+            Some(r) => r,
+            None => return self.reply("{\"ok\":0}"),
+        };
+
+        let prepared = mail::prepare(
+            &mut *self.store.write().unwrap(),
+            self.keyring,
+            config,
+            entry,
+            label,
+            expiry,
+            scope);
+        let outgoing = match prepared {
+            Err(e) => return self.reply_err(&e),
+            Ok(o) => o,
+        };
+
+        // Sent in the background, the outcome follows as a delivery event
+        let delivery = mail::pending(&outgoing);
+        let sender = self.sender.clone();
+        mail::dispatch(self.store, config, outgoing, move |delivery| {
+            let event = json!({
+                "cmd": "delivery",
+                "delivery": delivery,
+            }).to_string();
+            let _ = sender.send(event); // The client may have gone
+        });
+
+        let s = json!({
+            "ok": 1,
+            "delivery": delivery,
+        }).to_string();
+        self.reply(s)
+    }
+
+    fn send_deliveries(&self) -> ws::Result<()> {
+        let s = json!({
+            "ok": 1,
+            "deliveries": self.store.read().unwrap().deliveries(),
+        }).to_string();
+        self.reply(s)
+    }
+
+    fn revoke_key(&self, data: &Value) -> ws::Result<()> {
+        let id = match data["id"].as_u64() {
+            Some(id) => id as u32,
+            None => return self.reply("{\"ok\":0}"),
+        };
+
+        // Legacy keys (id 0) are revoked by entry
+        let entry = data["entry"].as_i64().unwrap_or(0) as i32;
+
+        if let Err(e) = self.store.write().unwrap().revoke_key(id, entry) {
+            return self.reply_err(&e);
+        }
+        self.reply("{\"ok\":1}")
+    }
+
+    fn send_keys(&self, data: &Value) -> ws::Result<()> {
+        let s = self.store.read().unwrap();
+        let entry = data["entry"].as_i64().map(|e| e as i32);
+        let keys: Vec<Value> = s.keys(entry).iter().map(|k| json!({
+            "claims": k,
+            "revoked": s.is_revoked(k),
+            "expired": k.is_expired(),
+        })).collect();
+
+        let s = json!({
+            "ok": 1,
+            "keys": keys,
+        }).to_string();
+        self.reply(s)
+    }
+
+    fn send_audit(&self, data: &Value) -> ws::Result<()> {
+        let from = data["from"].as_u64().unwrap_or(0);
+        let limit = data["limit"].as_u64().map_or(AUDIT_PAGE, |l| l as usize).min(AUDIT_PAGE);
+        let records = self.store.read().unwrap().audit(from, limit);
+
+        let s = json!({
+            "ok": 1,
+            "records": records,
+            "next": records.last().map_or(from, |r| r.seq + 1),
+        }).to_string();
+        self.reply(s)
+    }
+
+    fn auth(&mut self, data: &Value) -> ws::Result<()> {
+        if LIMITER.is_locked(&self.addr) {
+            self.reply("{\"ok\":0,\"error\":\"Locked\"}")?;
+            return self.sender.close(ws::CloseCode::Policy);
+        }
+
+        self.principal = self.authenticate(data);
+        if self.principal.is_some() {
+            LIMITER.succeed(&self.addr);
+        } else {
+            LIMITER.fail(&self.addr, &self.config.ratelimit);
+        }
+
+        // Lets the client show files of entries not yet published
+        let expiry = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + PREVIEW_VALIDITY;
+        let preview = self.keyring.preview_token(self.limited(), expiry);
+
+        match self.principal {
+            Some(Principal::Admin(ref name, role)) => {
+                let s = json!({
+                    "ok": 1,
+                    "name": name,
+                    "role": role,
+                    "preview": preview,
+                }).to_string();
+                self.reply(s)?;
+            },
+            Some(Principal::Club(ref claims)) => {
+                let s = json!({
+                    "ok": 1,
+                    "limited": claims.entry,
+                    "scope": claims.scope,
+                    "preview": preview,
+                }).to_string();
+                self.reply(s)?;
+            },
+            None => {
+                self.reply("{\"ok\":0}")?;
+                return self.sender.close(ws::CloseCode::Normal);
+            },
+        }
+
+        // Only authenticated connections receive broadcasts
         SENDERS
             .write()
             .unwrap()
-            .insert(self.sender.token(), self.sender.clone());
-
-        let res = shake.request.resource();
-        if res == format!("/{}", self.config.secret) {
-            // Is administrative account
-            self.sender.send("{\"ok\":1}")?;
-            return Ok(())
-        } else if res.len() > 1 && res.starts_with('/') {
-            if let Some(id) = self.try_decrypt_key(&res[1..]) {
-                self.limited = Some(id);
-                self.sender.send(format!("{{\"ok\":1,\"limited\":{}}}", id))?;
-                return Ok(())
-            }
-        }
-        self.sender.send("{\"ok\":0}")?;
-        self.sender.close(ws::CloseCode::Normal)?;
+            .insert(self.sender.token(), (self.sender.clone(), self.limited()));
         Ok(())
     }
 
-    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
-        let data: Value = match serde_json::from_slice(&msg.into_data()) {
-            Err(e) => return Err(err_to_wserr(e, "Deserialization failed")),
-            Ok(d) => d,
+    fn dispatch(&mut self, data: Value, permission: Permission) -> ws::Result<()> {
+        if !self.permits(permission) {
+            return self.reply_err(&StoreError::Denied);
+        }
+
+        // Club keys may be revoked or expire while connected
+        let revoked = match self.principal {
+            Some(Principal::Club(ref claims)) => !self.is_valid(claims),
+            _ => false,
         };
+
+        if revoked {
+            self.reply_err(&StoreError::Denied)?;
+            return self.sender.close(ws::CloseCode::Policy);
+        }
+
         if data["cmd"] == "list" {
             self.list()
         } else if data["cmd"] == "commit" || data["cmd"] == "discard" {
-            if self.limited.is_some() {
-                self.sender.send("{\"ok\":0}")?; // Denied
-                return Ok(())
-            }
-
             let id = match data["id"] {
                 Value::Number(ref i) => match i.as_i64() {
                     Some(i) => i as i32,
                     None => {
-                        self.sender.send("{\"ok\":0}")?;
+                        self.reply("{\"ok\":0}")?;
                         return Ok(());
                     }
                 },
                 _ => {
-                    self.sender.send("{\"ok\":0}")?;
+                    self.reply("{\"ok\":0}")?;
                     return Ok(());
                 },
             };
@@ -255,28 +1078,17 @@ impl ws::Handler for Handler {
             let mut s = self.store.write().unwrap();
             if data["cmd"] == "commit" {
                 if let Err(e) = s.commit(id) {
-                    return Err(err_to_wserr(e, "Storage failure"))
+                    return self.reply_err(&e);
                 }
             } else {
                 s.discard(id);
             };
 
-            let payload = match serde_json::to_value(s.pull_fetch(id)) {
-                Err(e) => return Err(err_to_wserr(e, "Serialization Failed")),
-                Ok(p) => p,
-            };
-
-            let content = json!({
-                "cmd": "update",
-                "id": id,
-                "payload": payload,
-            }).to_string();
-
-            self.sender.broadcast(content);
-            self.sender.send("{\"ok\":1}")
+            self.broadcast_updates(&s, &[id])?;
+            self.reply("{\"ok\":1}")
         } else if data["cmd"] == "len" {
             let len = self.store.read().unwrap().len();
-            self.sender.send(format!("{{\"ok\":1,\"len\":{}}}", len))?;
+            self.reply(format!("{{\"ok\":1,\"len\":{}}}", len))?;
             Ok(())
         } else if data["cmd"] == "put" {
             // TODO: Can we remove this clone?
@@ -284,118 +1096,152 @@ impl ws::Handler for Handler {
         } else if data["cmd"] == "del" {
             // TODO: Can we remove this clone?
             self.del(data["target"].clone())
-        } else if data["cmd"] == "upload" {
-            // Generate uuid
-            let uuid = Uuid::new_v4();
-            let basename = uuid.hyphenated();
-
-            let ext = match data["ext"] {
-                Value::String(ref s) => s,
-                _ => {
-                    self.sender.send("{\"ok\":0}")?;
-                    return Ok(());
-                },
-            };
-
-            let mut entry: Option<i32> = None;
-            if let Value::Number(ref n) = data["entry"] {
-                if let Some(i) = n.as_i64() {
-                    entry = Some(i as i32);
-                };
-            };
-
-            let entry = match entry {
-                Some(e) => e,
-                _ => {
-                    self.sender.send("{\"ok\":0}")?;
-                    return Ok(());
-                }
-            };
-
-            if let Some(id) = self.limited {
-                if id != entry {
-                    self.sender.send("{\"ok\":0}")?;
-                    return Ok(());
-                }
-            }
-
-            let fullname = format!("{}.{}.{}", entry, basename, ext);
-            let path = Path::new("static/store/").join(fullname);
-            let opening = OpenOptions::new().write(true).create(true).open(path);
-            self.uploading = match opening {
-                Ok(f) => Some(f),
-                Err(e) => return Err(err_to_wserr(e, "Cannot create file")),
+        } else if data["cmd"] == "reorderFiles" {
+            self.reorder_files(&data)
+        } else if data["cmd"] == "uploadStart" {
+            self.start_upload(&data)
+        } else if data["cmd"] == "uploadChunk" {
+            // The data follows as a binary message, which is then acknowledged
+            self.chunk = match (data["id"].as_str(), data["offset"].as_u64()) {
+                (Some(id), Some(offset)) => Some((id.to_owned(), offset)),
+                _ => None,
             };
-            self.sender.send("{\"ok\":1}")?;
             Ok(())
-        } else if data["cmd"] == "files" {
-            self.files(data["entry"].clone())
-        } else if data["cmd"] == "genKey" {
-            let number = match data["target"] {
-                Value::Number(ref n) => {
-                    if let Some(n) = n.as_i64() { n as i32 }
-                    else {
-                        self.sender.send("{\"ok\":0}")?;
-                        return Ok(());
-                    }
-                },
-                _ => {
-                    self.sender.send("{\"ok\":0}")?;
-                    return Ok(());
-                },
-            };
-            let key = match self.generate_key(number) {
-                Err(_) => {
-                    self.sender.send("{\"ok\":0}")?;
-                    return Ok(());
-                },
-                Ok(s) => s
-            };
+        } else if data["cmd"] == "uploadStatus"
+            || data["cmd"] == "uploadFinish"
+            || data["cmd"] == "uploadCancel" {
+            self.upload_session(&data)
+        } else if data["cmd"] == "categories" {
             let s = json!({
                 "ok": 1,
-                "key": key,
+                "categories": self.store.read().unwrap().categories(),
             }).to_string();
-            self.sender.send(s)?;
-            Ok(())
+            self.reply(s)
+        } else if data["cmd"] == "createCategory"
+            || data["cmd"] == "updateCategory"
+            || data["cmd"] == "mergeCategory" {
+            self.edit_category(&data)
+        } else if data["cmd"] == "tags" {
+            let s = json!({
+                "ok": 1,
+                "tags": self.store.read().unwrap().tags(),
+            }).to_string();
+            self.reply(s)
+        } else if data["cmd"] == "renameTag" || data["cmd"] == "mergeTags" {
+            self.edit_tags(&data)
+        } else if data["cmd"] == "vocabulary" {
+            let s = json!({
+                "ok": 1,
+                "vocabulary": self.store.read().unwrap().vocabulary(),
+            }).to_string();
+            self.reply(s)
+        } else if data["cmd"] == "addVocabulary" || data["cmd"] == "removeVocabulary" {
+            self.edit_vocabulary(&data)
+        } else if data["cmd"] == "accounts" {
+            self.send_accounts(&self.store.read().unwrap())
+        } else if data["cmd"] == "putAccount" || data["cmd"] == "delAccount" {
+            self.edit_accounts(&data)
+        } else if data["cmd"] == "files" {
+            self.files(data["entry"].clone())
+        } else if data["cmd"] == "genKey" {
+            self.generate_key(&data)
+        } else if data["cmd"] == "keys" {
+            self.send_keys(&data)
+        } else if data["cmd"] == "revokeKey" {
+            self.revoke_key(&data)
+        } else if data["cmd"] == "sendKey" {
+            self.send_key(&data)
+        } else if data["cmd"] == "deliveries" {
+            self.send_deliveries()
+        } else if data["cmd"] == "audit" {
+            self.send_audit(&data)
         } else if data["cmd"] == "deleteFile" {
-            let filename = match data["target"] {
-                Value::String(ref s) => s,
-                _ => {
-                    self.sender.send("{\"ok\":0}")?;
-                    return Ok(());
-                },
-            };
-            let path = Path::new("static/store/").join(filename);
-            if let Err(e) = remove_file(path) {
-                self.sender.send("{\"ok\":0}")?;
-            } else {
-                self.sender.send("{\"ok\":1}")?;
-            }
-            Ok(())
+            self.delete_file(&data)
+        } else if data["cmd"] == "deleted" {
+            self.deleted()
+        } else if data["cmd"] == "restore" {
+            self.restore(&data)
+        } else if data["cmd"] == "purge" {
+            self.purge(&data)
+        } else if data["cmd"] == "orphans" || data["cmd"] == "collectOrphans" {
+            self.orphans(&data)
         } else {
             Ok(())
         }
     }
 
-    fn on_frame(&mut self, frame: Frame) -> ws::Result<Option<Frame>> {
-        if frame.is_control() {
-            return Ok(Some(frame));
+    pub fn try_decrypt_key(&self, key: &str) -> Option<Claims> {
+        self.keyring.try_decrypt_key(key)
+    }
+}
+
+impl ws::Handler for Handler {
+    fn on_open(&mut self, shake: Handshake) -> ws::Result<()> {
+        // Behind trusted proxies, the client address comes from X-Forwarded-For
+        let forwarded = shake.request.header("X-Forwarded-For").and_then(|h| std::str::from_utf8(h).ok());
+        let addr = ratelimit::client_addr(shake.peer_addr.map(|a| a.ip()), forwarded, &self.config.trusted_proxies);
+        self.addr = addr.map(|a| a.to_string()).unwrap_or_default();
+
+        if !LIMITER.connect(&self.addr, &self.config.ratelimit) {
+            return self.sender.close(ws::CloseCode::Policy);
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        // Closed
+        SENDERS
+            .write()
+            .unwrap()
+            .remove(&self.sender.token());
+    }
+
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        if msg.is_binary() {
+            return self.receive_chunk(msg.into_data());
+        }
+
+        let data: Value = match serde_json::from_slice(&msg.into_data()) {
+            Err(e) => return Err(err_to_wserr(e, "Deserialization failed")),
+            Ok(d) => d,
         };
 
-        let file = match self.uploading {
-            None => return Ok(Some(frame)),
-            Some(ref mut f) => f,
+        // Credentials are only accepted here, never in the request path
+        if data["cmd"] == "auth" {
+            return self.auth(&data);
+        }
+
+        let permission = match data["cmd"].as_str().and_then(permission_of) {
+            None => return Ok(()), // Unknown command
+            Some(p) => p,
         };
 
-        match file.write_all(frame.payload()) {
-            Err(e) => Err(ws::Error::new(ws::ErrorKind::Custom(Box::new(e)), "Writing failed")),
-            Ok(_) => {
-                if frame.is_final() {
-                    self.uploading = None; // Drop the file to close it
-                    self.sender.send("{\"ok\":1}")?;
-                }
-                Ok(None)
-            },
+        // Every action beyond reading is recorded, including refused ones
+        if is_audited(data["cmd"].as_str().unwrap(), permission) {
+            self.auditing = self.actor().map(|actor| {
+                let mut record = AuditRecord::new(actor, data["cmd"].as_str().unwrap().to_owned());
+                let (target, detail) = audit_target(&data);
+                record.target = target;
+                record.detail = detail;
+                record
+            });
         }
+
+        let result = self.dispatch(data, permission);
+
+        if let Some(mut record) = self.auditing.take() {
+            let outcome = self.outcome.borrow_mut().take();
+            match (&result, outcome) {
+                (&Err(ref e), _) => record.error = Some(e.to_string()),
+                (&Ok(_), Some(outcome)) => {
+                    record.ok = outcome["ok"] == 1;
+                    record.error = outcome["error"].as_str().map(str::to_owned);
+                },
+                (&Ok(_), None) => record.ok = true,
+            }
+            self.store.write().unwrap().append_audit(record);
+        }
+
+        result
     }
 }
@@ -0,0 +1,30 @@
+extern crate crs;
+
+use crs::account::{Account, Role};
+use crs::store;
+use std::env;
+use std::error::Error;
+use std::io;
+use std::process;
+
+// Creates or updates an administrative account, reading the password from stdin
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let role: Option<Role> = args.get(2).and_then(|r| r.parse().ok());
+    if args.len() != 3 || role.is_none() {
+        eprintln!("Usage: account <name> <viewer|reviewer|editor|superadmin>");
+        process::exit(1);
+    }
+
+    let mut password = String::new();
+    io::stdin().read_line(&mut password).unwrap();
+    let password = password.trim_right_matches(|c| c == '\r' || c == '\n');
+
+    let mut store = store::Store::new();
+    let account = Account::create(args[1].clone(), password, role.unwrap()).unwrap();
+    if let Err(e) = store.put_account(&account) {
+        eprintln!("{}", e.description());
+        process::exit(1);
+    }
+    println!("Account {} saved.", args[1]);
+}
@@ -0,0 +1,32 @@
+extern crate crs;
+extern crate csv;
+
+use crs::store;
+use std::env;
+use std::io;
+
+const PAGE: usize = 1000;
+
+// Exports the audit log as CSV, optionally starting from the sequence given by --from N
+fn main() {
+    let store = store::Store::new();
+    let args: Vec<String> = env::args().collect();
+    let mut from = args.iter()
+        .position(|a| a == "--from")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+
+    let mut writer = csv::Writer::from_writer(io::stdout());
+    loop {
+        let records = store.audit(from, PAGE);
+        for r in &records {
+            writer.serialize(r).unwrap();
+        }
+
+        match records.last() {
+            Some(r) if records.len() == PAGE => from = r.seq + 1,
+            _ => break,
+        }
+    }
+}
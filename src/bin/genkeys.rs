@@ -7,24 +7,107 @@ extern crate serde;
 
 use crs::store;
 use crs::config;
-use crs::key;
+use crs::key::{KeyRing, Scope};
+use crs::mail;
 
+use std::env;
+use std::error::Error;
 use std::io;
 use std::str::Split;
 
 fn main() {
-    let store = store::Store::new();
+    let mut store = store::Store::new();
     let config = config::Config::load();
-    let entries = store.filter::<Split<&str>>(None, None);
-
+    let keyring = KeyRing::from_config(&config);
     let mut writer = csv::Writer::from_writer(io::stdout());
+
+    // Reissues every live key sealed with a previous secret, as well as legacy keys, and revokes the old ones
+    if env::args().any(|a| a == "--reissue") {
+        let stale: Vec<_> = store.keys(None)
+            .into_iter()
+            .filter(|k| k.ring != keyring.current_id() && !k.is_expired() && !store.is_revoked(k))
+            .collect();
+
+        for old in &stale {
+            let e = match store.fetch(old.entry) {
+                Some(e) => e,
+                None => continue,
+            };
+            let claims = match store.issue_key(old.entry, old.label.clone(), old.expiry, old.scope, keyring.current_id()) {
+                Ok(c) => c,
+                Err(_) => continue, // Deleted entry
+            };
+            store.revoke_key(old.id, old.entry).unwrap();
+            writer.serialize(
+                (
+                    e.id(),
+                    e.name(),
+                    e.name_eng(),
+                    claims.id,
+                    keyring.generate_key(&claims).unwrap()
+                )
+            ).unwrap();
+        }
+
+        // Keys issued before keys were recorded carry no id. Entries left without any live recorded key
+        // may still rely on one, so they get a replacement, and their legacy keys are revoked
+        let entries = store.filter::<Split<&str>>(None, None, None);
+        for e in &entries {
+            let has_live = store.keys(Some(e.id()))
+                .iter()
+                .any(|k| !k.is_expired() && !store.is_revoked(k));
+            if has_live || store.is_legacy_revoked(e.id()) {
+                continue;
+            }
+
+            let claims = match store.issue_key(e.id(), None, None, Scope::ALL, keyring.current_id()) {
+                Ok(c) => c,
+                Err(_) => continue, // Deleted entry
+            };
+            store.revoke_key(0, e.id()).unwrap();
+            writer.serialize(
+                (
+                    e.id(),
+                    e.name(),
+                    e.name_eng(),
+                    claims.id,
+                    keyring.generate_key(&claims).unwrap()
+                )
+            ).unwrap();
+        }
+        return;
+    }
+
+    let entries = store.filter::<Split<&str>>(None, None, None);
+
+    // Mails a one-time link to every entry with a contact, instead of printing keys
+    if env::args().any(|a| a == "--mail") {
+        let mail_config = config.mail.as_ref().expect("Mail is not configured");
+        let mailer = mail::mailer(mail_config);
+        for e in entries.iter().filter(|e| e.contact().map_or(false, |c| !c.is_empty())) {
+            let outgoing = match mail::prepare(&mut store, &keyring, mail_config, e.id(), None, None, Scope::ALL) {
+                Ok(o) => o,
+                Err(err) => {
+                    eprintln!("Skipping entry {}: {}", e.id(), err.description());
+                    continue;
+                },
+            };
+            let delivery = mail::deliver(&*mailer, &outgoing);
+            mail::complete(&mut store, &delivery);
+            writer.serialize((e.id(), e.name(), delivery.to, delivery.ok, delivery.error)).unwrap();
+        }
+        return;
+    }
+
     for e in &entries {
+        let claims = store.issue_key(e.id(), None, None, Scope::ALL, keyring.current_id()).unwrap();
         writer.serialize(
             (
                 e.id(),
                 e.name(),
                 e.name_eng(),
-                key::generate_key(e.id(), config.secret.as_bytes()).unwrap()
+                claims.id,
+                keyring.generate_key(&claims).unwrap()
             )
         ).unwrap();
     };
@@ -13,9 +13,9 @@ fn main() {
     for result in rdr.deserialize() {
         let raw: store::RawEntry = result.unwrap();
         curid += 1;
-        let entry: store::Entry = raw.extend(curid);
+        let entry: store::Entry = raw.extend(curid).unwrap();
         println!("Inserting: {:?}", entry);
-        store.stash(entry, false).unwrap();
+        store.stash(entry, false, None).unwrap();
         store.commit(curid).unwrap();
     }
 }
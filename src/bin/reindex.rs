@@ -0,0 +1,28 @@
+extern crate crs;
+
+use crs::store;
+use std::env;
+use std::process;
+
+// Checks the persisted search index against the entries, or rebuilds it with --rebuild
+fn main() {
+    let mut store = store::Store::new();
+
+    if env::args().any(|a| a == "--rebuild") {
+        store.rebuild_index().unwrap();
+        println!("Index rebuilt.");
+        return;
+    }
+
+    let mismatched = store.verify_index().unwrap();
+    if mismatched.is_empty() {
+        println!("Index is consistent.");
+        return;
+    }
+
+    for key in &mismatched {
+        println!("Mismatched: {}", key);
+    }
+    println!("{} inconsistent keys found, run with --rebuild to fix.", mismatched.len());
+    process::exit(1);
+}
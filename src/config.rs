@@ -1,5 +1,6 @@
 use serde_yaml;
 use std::fs::File;
+use std::net::IpAddr;
 
 const CONFIG_PATH: &str = "./config.yml";
 
@@ -9,6 +10,105 @@ pub struct ServerConfig {
     pub port: u16,
 }
 
+// Limits on WebSocket connections and authentication, per client address
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub connections: u32, // Connections allowed within `window`
+    pub failures: u32, // Failed authentications allowed within `window` before locking out
+    pub window: u64, // s
+    pub lockout: u64, // s
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            connections: 30,
+            failures: 5,
+            window: 300,
+            lockout: 900,
+        }
+    }
+}
+
+// Enforced while files are uploaded. Only the allowed types are published, limits are not
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct UploadPolicy {
+    #[serde(skip_serializing)]
+    pub max_size: u64, // Bytes per file
+    #[serde(skip_serializing)]
+    pub quota: u64, // Bytes per entry
+    pub allowed: Vec<String>, // Extensions, checked against the content where recognizable
+    #[serde(skip_serializing)]
+    pub session_timeout: u64, // s, after which unfinished uploads are removed
+    #[serde(skip_serializing)]
+    pub orphan_grace: u64, // s, for which files no entry refers to are kept
+    #[serde(skip_serializing)]
+    pub gc_interval: u64, // s, between collections of orphaned files
+    #[serde(skip_serializing)]
+    pub max_pixels: u64, // Width times height of uploaded images, checked before they are decoded
+}
+
+impl Default for UploadPolicy {
+    fn default() -> UploadPolicy {
+        UploadPolicy {
+            max_size: 10 << 20,
+            quota: 100 << 20,
+            allowed: vec!["jpg", "jpeg", "png", "gif", "webp"]
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            session_timeout: 24 * 3600,
+            orphan_grace: 7 * 24 * 3600,
+            gc_interval: 24 * 3600,
+            max_pixels: 40_000_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp(SmtpConfig),
+    Maildir(String), // Directory, for testing
+}
+
+// Delivery of club keys to the contact of each entry
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MailConfig {
+    pub from: String, // e.g. "Registry <noreply@example.com>"
+    pub transport: MailTransport,
+    pub base_url: String, // Public URL of the admin page, without tailing slash
+    #[serde(default = "default_link_validity")]
+    pub link_validity: u64, // s
+    pub subject: Option<String>,
+    pub template: Option<String>, // Path to the message template
+}
+
+fn default_link_validity() -> u64 {
+    7 * 24 * 3600
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PreviousSecret {
+    pub id: u32,
+    pub secret: String,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub web: ServerConfig,
@@ -16,7 +116,31 @@ pub struct Config {
 
     #[serde(skip_serializing)] // Avoids accidental leak
     pub secret: String,
+
+    // Key ring for secret rotation, see config.default.yml
+    #[serde(default, skip_serializing)]
+    pub secret_id: u32,
+    #[serde(default, skip_serializing)]
+    pub previous_secrets: Vec<PreviousSecret>,
+
     pub proxied: Option<String>,
+
+    // Addresses of reverse proxies whose X-Forwarded-For is believed
+    #[serde(default, skip_serializing)]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    // Restricts clubs to the managed tag vocabulary
+    #[serde(default)]
+    pub controlled_tags: bool,
+
+    #[serde(default, skip_serializing)]
+    pub ratelimit: RateLimitConfig,
+
+    #[serde(default, skip_serializing)]
+    pub mail: Option<MailConfig>,
+
+    #[serde(default)]
+    pub upload: UploadPolicy,
 }
 
 impl Config {
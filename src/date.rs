@@ -0,0 +1,231 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+// Calendar date, serialized as YYYY-MM-DD
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    year: i32,
+    month: u32,
+    day: u32,
+}
+
+#[derive(Debug)]
+pub struct DateError;
+
+impl fmt::Display for DateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DateError")
+    }
+}
+
+impl Error for DateError {
+    fn description(&self) -> &'static str {
+        "Invalid date: expecting YYYY-MM-DD"
+    }
+}
+
+fn is_leap(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 => if is_leap(year) { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Formats a Unix timestamp as an HTTP date, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(timestamp: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let days = timestamp / 86400;
+    let secs = timestamp % 86400;
+
+    let mut year = 1970;
+    let mut remaining = days as u32;
+    loop {
+        let len = if is_leap(year) { 366 } else { 365 };
+        if remaining < len {
+            break;
+        }
+        remaining -= len;
+        year += 1;
+    }
+
+    let mut month = 1;
+    while remaining >= days_in_month(year, month) {
+        remaining -= days_in_month(year, month);
+        month += 1;
+    }
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        remaining + 1,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60)
+}
+
+impl Date {
+    // Stands in for creation dates stored before they were validated, which were free-form
+    pub const UNKNOWN: Date = Date { year: 0, month: 1, day: 1 };
+
+    pub fn new(year: i32, month: u32, day: u32) -> Result<Date, DateError> {
+        if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) {
+            return Err(DateError);
+        }
+        Ok(Date { year, month, day })
+    }
+
+    pub fn year(&self) -> i32 {
+        self.year
+    }
+}
+
+impl FromStr for Date {
+    type Err = DateError;
+
+    fn from_str(s: &str) -> Result<Date, DateError> {
+        let segs: Vec<&str> = s.split('-').collect();
+        if segs.len() != 3 || segs[0].len() != 4 || segs[1].len() != 2 || segs[2].len() != 2 {
+            return Err(DateError);
+        }
+
+        if !segs.iter().all(|seg| seg.bytes().all(|b| b.is_ascii_digit())) {
+            return Err(DateError);
+        }
+
+        Date::new(
+            segs[0].parse().map_err(|_| DateError)?,
+            segs[1].parse().map_err(|_| DateError)?,
+            segs[2].parse().map_err(|_| DateError)?)
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Date {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Date, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e: DateError| de::Error::custom(e.description()))
+    }
+}
+
+// Lenient counterparts of the Deserialize impl, for records written before dates were validated.
+// Unparseable dates become Date::UNKNOWN, so that a disbanded club stays disbanded. Only empty disbandment dates are None
+pub fn lenient<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Date, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    Ok(s.trim().parse().unwrap_or(Date::UNKNOWN))
+}
+
+pub fn lenient_opt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Date>, D::Error> {
+    let s = Option::<String>::deserialize(deserializer)?;
+    Ok(s.filter(|s| !s.trim().is_empty()).map(|s| s.trim().parse().unwrap_or(Date::UNKNOWN)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!("2018-03-14".parse::<Date>().unwrap(), Date::new(2018, 3, 14).unwrap());
+        assert_eq!("2018-03-14".parse::<Date>().unwrap().to_string(), "2018-03-14");
+        assert_eq!("0000-01-01".parse::<Date>().unwrap(), Date::UNKNOWN);
+    }
+
+    #[test]
+    fn rejects_malformed_dates() {
+        for s in &["", "2018", "2018-3-14", "18-03-14", "2018-03-14-01", "2018/03/14", "+018-03-14", "2018-0a-14", " 2018-03-14"] {
+            assert!(s.parse::<Date>().is_err(), "{:?} should be rejected", s);
+        }
+    }
+
+    #[test]
+    fn rejects_days_outside_the_month() {
+        assert!("2018-00-10".parse::<Date>().is_err());
+        assert!("2018-13-10".parse::<Date>().is_err());
+        assert!("2018-04-00".parse::<Date>().is_err());
+        assert!("2018-04-31".parse::<Date>().is_err());
+        assert!("2018-02-29".parse::<Date>().is_err());
+        assert!("2016-02-29".parse::<Date>().is_ok());
+        assert!("1900-02-29".parse::<Date>().is_err());
+        assert!("2000-02-29".parse::<Date>().is_ok());
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(1_000_000_000), "Sun, 09 Sep 2001 01:46:40 GMT");
+    }
+
+    #[test]
+    fn formats_leap_days() {
+        assert_eq!(http_date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(http_date(951868800), "Wed, 01 Mar 2000 00:00:00 GMT");
+        assert_eq!(http_date(1330473600), "Wed, 29 Feb 2012 00:00:00 GMT");
+        assert_eq!(http_date(4107542400), "Mon, 01 Mar 2100 00:00:00 GMT");
+        assert_eq!(http_date(1483228799), "Sat, 31 Dec 2016 23:59:59 GMT");
+    }
+
+    #[test]
+    fn orders_chronologically() {
+        let dates: Vec<Date> = ["2017-12-31", "2018-01-01", "2018-01-02", "2018-02-01"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert!(dates.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[derive(Deserialize)]
+    struct Legacy {
+        #[serde(deserialize_with="lenient")]
+        creation: Date,
+        #[serde(default, deserialize_with="lenient_opt")]
+        disbandment: Option<Date>,
+    }
+
+    fn legacy(json: &str) -> Legacy {
+        ::serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn reads_legacy_dates_leniently() {
+        let l = legacy(r#"{"creation":" 2015-09-01 ","disbandment":"2018-06-30"}"#);
+        assert_eq!(l.creation, Date::new(2015, 9, 1).unwrap());
+        assert_eq!(l.disbandment, Some(Date::new(2018, 6, 30).unwrap()));
+
+        let l = legacy(r#"{"creation":"2015年秋","disbandment":"2018年春"}"#);
+        assert_eq!(l.creation, Date::UNKNOWN);
+        assert_eq!(l.disbandment, Some(Date::UNKNOWN));
+
+        let l = legacy(r#"{"creation":"2015-09-01","disbandment":"  "}"#);
+        assert_eq!(l.disbandment, None);
+
+        let l = legacy(r#"{"creation":"","disbandment":null}"#);
+        assert_eq!(l.creation, Date::UNKNOWN);
+        assert_eq!(l.disbandment, None);
+
+        assert_eq!(legacy(r#"{"creation":"2015-09-01"}"#).disbandment, None);
+    }
+}
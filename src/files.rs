@@ -0,0 +1,811 @@
+use config::UploadPolicy;
+use image::{self, gif, jpeg, png, FilterType, GenericImage, ImageDecoder, ImageFormat};
+use key::{from_hex, to_hex};
+use ring::digest;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use store::{Entry, FileRecord, Store, StoreError};
+use uuid::Uuid;
+
+// Contents of uploaded files, named by their SHA-256, so that identical uploads share storage
+pub const BLOB_DIR: &str = "blobs";
+// Where files were kept under their own names, before storage became content addressed
+pub const LEGACY_DIR: &str = "static/store";
+// Unfinished uploads, kept out of the public directory
+pub const PARTIAL_DIR: &str = "uploads";
+
+const MAX_EXT_LEN: usize = 10;
+
+// Smaller versions generated for images, as (size, longest side in px)
+pub const RENDITIONS: &[(&str, u32)] = &[("thumb", 128), ("medium", 512)];
+
+// Bytes needed to recognize any of the signatures below
+const SNIFF_LEN: usize = 12;
+
+lazy_static! {
+    // Held while generating renditions, so that only one image is decoded at a time
+    static ref RENDERING: Mutex<()> = Mutex::new(());
+}
+
+// Uploaded files are named <entry>.<uuid>.<ext>
+pub struct FileName<'a> {
+    pub entry: i32,
+    pub uuid: &'a str,
+    pub ext: &'a str,
+}
+
+fn valid_ext(ext: &str) -> bool {
+    !ext.is_empty() && ext.len() <= MAX_EXT_LEN && ext.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+impl<'a> FileName<'a> {
+    pub fn parse(name: &'a str) -> Option<FileName<'a>> {
+        let mut segs = name.splitn(3, '.');
+        let entry = segs.next()?.parse().ok()?;
+        let uuid = segs.next()?;
+        let ext = segs.next()?;
+
+        if Uuid::parse_str(uuid).is_err() || uuid.len() != 36 || !valid_ext(ext) {
+            return None;
+        }
+
+        Some(FileName { entry, uuid, ext })
+    }
+}
+
+// A fresh name for a file uploaded to the entry, with the extension normalized
+pub fn new_name(entry: i32, ext: &str) -> Result<String, StoreError> {
+    let ext = ext.trim_left_matches('.').to_ascii_lowercase();
+    if !valid_ext(&ext) {
+        return Err(StoreError::InvalidFileName);
+    }
+    Ok(format!("{}.{}.{}", entry, Uuid::new_v4().hyphenated(), ext))
+}
+
+// Path of the blob with the given hex digest. Only well-formed digests are accepted, so the path never leaves BLOB_DIR
+pub fn blob_path(sha256: &str) -> Result<PathBuf, StoreError> {
+    if sha256.len() != digest::SHA256_OUTPUT_LEN * 2 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(StoreError::InvalidFileName);
+    }
+    Ok(Path::new(BLOB_DIR).join(&sha256[0..2]).join(sha256))
+}
+
+// Moves the file into the blob store, unless the same content is already there.
+// Callers hold the store, and record the file before releasing it, so that the blob cannot be collected meanwhile
+fn store_blob(path: &Path, sha256: &str) -> Result<(), StoreError> {
+    let blob = blob_path(sha256)?;
+    let result = if blob.exists() {
+        fs::remove_file(path)
+    } else {
+        fs::create_dir_all(blob.parent().unwrap()).and_then(|_| fs::rename(path, &blob))
+    };
+    result.map_err(|_| StoreError::SystemError)
+}
+
+// Whether a connection limited to `limited` may touch the file
+pub fn permits(name: &str, limited: Option<i32>) -> bool {
+    match (FileName::parse(name), limited) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(f), Some(entry)) => f.entry == entry,
+    }
+}
+
+// Path of a rendition of the blob, stored next to it as PNG
+pub fn rendition_path(sha256: &str, size: &str) -> Result<PathBuf, StoreError> {
+    if !RENDITIONS.iter().any(|&(s, _)| s == size) {
+        return Err(StoreError::NotFound);
+    }
+    let blob = blob_path(sha256)?;
+    Ok(blob.with_file_name(format!("{}.{}.png", sha256, size)))
+}
+
+// Marks a blob whose renditions have been generated, or found unnecessary, so that it is never decoded again
+fn rendered_path(sha256: &str) -> Result<PathBuf, StoreError> {
+    let blob = blob_path(sha256)?;
+    Ok(blob.with_file_name(format!("{}.rendered", sha256)))
+}
+
+fn is_rendered(sha256: &str) -> bool {
+    rendered_path(sha256).map_or(true, |p| p.exists())
+}
+
+fn format_of(mime: &str) -> Option<ImageFormat> {
+    match mime {
+        "image/png" => Some(ImageFormat::PNG),
+        "image/jpeg" => Some(ImageFormat::JPEG),
+        "image/gif" => Some(ImageFormat::GIF),
+        "image/webp" => Some(ImageFormat::WEBP),
+        _ => None,
+    }
+}
+
+// Dimensions declared in the header of an image, read without decoding it
+fn dimensions(path: &Path, format: ImageFormat) -> Option<(u32, u32)> {
+    let reader = io::BufReader::new(File::open(path).ok()?);
+    match format {
+        ImageFormat::PNG => png::PNGDecoder::new(reader).dimensions().ok(),
+        ImageFormat::JPEG => jpeg::JPEGDecoder::new(reader).dimensions().ok(),
+        ImageFormat::GIF => gif::Decoder::new(reader).dimensions().ok(),
+        // The decoder would decode the whole frame. Only lossy WebP is supported,
+        // whose header carries 14 bit dimensions at a fixed offset
+        ImageFormat::WEBP => {
+            let mut head = [0u8; 30];
+            reader.take(30).read_exact(&mut head).ok()?;
+            if &head[12..16] != b"VP8 " || &head[23..26] != b"\x9d\x01\x2a" {
+                return None;
+            }
+            let width = (u32::from(head[26]) | u32::from(head[27]) << 8) & 0x3fff;
+            let height = (u32::from(head[28]) | u32::from(head[29]) << 8) & 0x3fff;
+            Some((width, height))
+        },
+        _ => None,
+    }
+}
+
+// Whether an image of the given type has no more than `max_pixels` pixels. Anything else passes
+fn within_limit(path: &Path, mime: &str, max_pixels: u64) -> bool {
+    format_of(mime)
+        .and_then(|format| dimensions(path, format))
+        .map_or(true, |(width, height)| u64::from(width) * u64::from(height) <= max_pixels)
+}
+
+// Generates the renditions of an image. Images already smaller than a rendition get none,
+// and are served as they are. Images above the pixel limit, or whose header cannot be read, are never decoded,
+// and content that cannot be decoded is left alone
+pub fn render(sha256: &str, mime: &str, max_pixels: u64) -> Result<(), StoreError> {
+    if is_rendered(sha256) {
+        return Ok(());
+    }
+
+    let blob = blob_path(sha256)?;
+    if let Some(format) = format_of(mime) {
+        match dimensions(&blob, format) {
+            Some((width, height)) if u64::from(width) * u64::from(height) <= max_pixels => {
+                resize(sha256, &blob, format, width.max(height))?;
+            },
+            _ => {},
+        }
+    }
+
+    File::create(rendered_path(sha256)?)
+        .map(|_| ())
+        .map_err(|_| StoreError::SystemError)
+}
+
+// Writes the renditions smaller than the image, whose longest side is `longest` px
+fn resize(sha256: &str, blob: &Path, format: ImageFormat, longest: u32) -> Result<(), StoreError> {
+    if RENDITIONS.iter().all(|&(_, side)| longest <= side) {
+        return Ok(());
+    }
+
+    let file = File::open(blob).map_err(|_| StoreError::SystemError)?;
+    let original = match image::load(io::BufReader::new(file), format) {
+        Ok(i) => i,
+        Err(_) => return Ok(()),
+    };
+
+    let (width, height) = original.dimensions();
+    for &(size, side) in RENDITIONS {
+        if width <= side && height <= side {
+            continue;
+        }
+
+        let resized = original.resize(side, side, FilterType::Lanczos3);
+        File::create(rendition_path(sha256, size)?)
+            .map_err(|_| StoreError::SystemError)
+            .and_then(|mut f| resized.save(&mut f, ImageFormat::PNG).map_err(|_| StoreError::SystemError))?;
+    }
+    Ok(())
+}
+
+// Renders the images, given as (sha256, mime), on a worker thread, keeping the decoding off the event loop
+// A failed rendition leaves the blob unmarked, to be tried again on the next startup
+pub fn render_later(images: Vec<(String, String)>, max_pixels: u64) {
+    thread::spawn(move || {
+        let _rendering = RENDERING.lock().unwrap();
+        for (sha256, mime) in images {
+            if let Err(e) = render(&sha256, &mime, max_pixels) {
+                println!("Rendering {} failed: {}", sha256, e.description());
+            }
+        }
+    });
+}
+
+// Removes a blob no longer referenced by any file, together with its renditions
+pub fn remove_blob(sha256: &str) -> Result<(), StoreError> {
+    for &(size, _) in RENDITIONS {
+        let _ = fs::remove_file(rendition_path(sha256, size)?);
+    }
+    let _ = fs::remove_file(rendered_path(sha256)?);
+    fs::remove_file(blob_path(sha256)?).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => StoreError::NotFound,
+        _ => StoreError::SystemError,
+    })
+}
+
+// Files left in LEGACY_DIR, optionally of a single entry, most recent first
+fn list(entry: Option<i32>) -> io::Result<Vec<String>> {
+    let mut collected = Vec::new();
+    for e in fs::read_dir(LEGACY_DIR)? {
+        let e = e?;
+        if !e.file_type()?.is_file() {
+            continue;
+        }
+
+        let filename = match e.file_name().into_string() {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+
+        let matches = match FileName::parse(&filename) {
+            None => false, // e.g. .gitkeep
+            Some(f) => entry.map_or(true, |id| f.entry == id),
+        };
+
+        if matches {
+            collected.push((e.metadata()?.modified()?, filename));
+        }
+    }
+
+    collected.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(collected.into_iter().map(|(_, f)| f).collect())
+}
+
+// Moves files left in LEGACY_DIR into the blob store, recording those uploaded before metadata was kept.
+// Reference counts are rebuilt afterwards, and missing renditions generated in the background.
+// Returns how many files were moved
+pub fn migrate(store: &mut Store, max_pixels: u64) -> Result<usize, StoreError> {
+    let mut count = 0;
+    for name in list(None).map_err(|_| StoreError::SystemError)? {
+        let path = Path::new(LEGACY_DIR).join(&name);
+        let created = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .map_err(|_| StoreError::SystemError)?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let (size, sha256) = File::open(&path)
+            .and_then(|mut f| digest_of(&mut f))
+            .map_err(|_| StoreError::SystemError)?;
+        let sha256 = to_hex(&sha256);
+
+        store_blob(&path, &sha256)?;
+        if store.file(&name).is_none() {
+            let f = FileName::parse(&name).unwrap();
+            store.record_file(&FileRecord {
+                entry: f.entry,
+                original: name.clone(),
+                mime: mime_of(f.ext).to_owned(),
+                size,
+                sha256,
+                uploader: None,
+                created,
+                caption: None,
+                name: name.clone(),
+            });
+        }
+        count += 1;
+    }
+
+    store.recount_blobs();
+
+    // Including images uploaded before renditions were generated
+    let mut seen = HashSet::new();
+    let unrendered = store.files(None)
+        .into_iter()
+        .filter(|r| format_of(&r.mime).is_some() && !is_rendered(&r.sha256) && seen.insert(r.sha256.clone()))
+        .map(|r| (r.sha256, r.mime))
+        .collect();
+    render_later(unrendered, max_pixels);
+    Ok(count)
+}
+
+// Deletes files no entry has referred to for `grace` seconds, and blobs left without any file.
+// Returns the names of the deleted files
+pub fn collect(store: &mut Store, grace: u64) -> Result<Vec<String>, StoreError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut removed = Vec::new();
+
+    for orphan in store.orphans() {
+        if orphan.since + grace > now {
+            continue;
+        }
+
+        if let Some(sha256) = store.forget_file(&orphan.name)? {
+            // May already be gone, which is what is wanted anyway
+            let _ = remove_blob(&sha256);
+        }
+        removed.push(orphan.name);
+    }
+
+    remove_stray_blobs(store, grace)?;
+    Ok(removed)
+}
+
+// Removes blobs and renditions no file refers to, e.g. left behind by a crash, once older than `grace` seconds
+fn remove_stray_blobs(store: &Store, grace: u64) -> Result<(), StoreError> {
+    let dirs = match fs::read_dir(BLOB_DIR) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(_) => return Err(StoreError::SystemError),
+        Ok(d) => d,
+    };
+
+    for dir in dirs.filter_map(Result::ok) {
+        let blobs = match fs::read_dir(dir.path()) {
+            Err(_) => continue,
+            Ok(b) => b,
+        };
+
+        for e in blobs.filter_map(Result::ok) {
+            let name = match e.file_name().into_string() {
+                Err(_) => continue,
+                Ok(n) => n,
+            };
+
+            // Renditions are named <sha256>.<size>.png, and markers <sha256>.rendered
+            let sha256 = name.split('.').next().unwrap();
+            let expired = e.metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .map_or(false, |age| age.as_secs() >= grace);
+
+            if expired && !store.is_blob_used(sha256) {
+                let _ = fs::remove_file(e.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+// A file as listed to clients. Attached files carry their position in the entry, and the caption given there
+#[derive(Serialize)]
+pub struct Listing {
+    #[serde(flatten)]
+    pub record: FileRecord,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub position: Option<usize>,
+}
+
+// Files attached to the entry in its own order, followed by the remaining uploads as listed
+pub fn arrange(records: Vec<FileRecord>, entry: Option<&Entry>) -> Vec<Listing> {
+    let attached = entry.map_or(&[][..], Entry::files);
+    let mut result: Vec<Listing> = records.into_iter().map(|mut record| {
+        if let Some(caption) = entry.and_then(|e| e.caption(&record.name)) {
+            record.caption = Some(caption.to_owned());
+        }
+        Listing {
+            position: attached.iter().position(|f| *f == record.name),
+            record,
+        }
+    }).collect();
+
+    // Stable, so that unattached files keep their order
+    result.sort_by_key(|l| l.position.unwrap_or(usize::max_value()));
+    result
+}
+
+// Type of the content by its magic bytes, if recognizable
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if head.starts_with(b"\xff\xd8\xff") {
+        Some("jpeg")
+    } else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if head.len() >= 12 && &head[0..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        Some("webp")
+    } else if head.starts_with(b"%PDF-") {
+        Some("pdf")
+    } else {
+        None
+    }
+}
+
+// Type an extension claims, for extensions with a known signature
+fn claimed(ext: &str) -> Option<&'static str> {
+    match ext {
+        "png" => Some("png"),
+        "jpg" | "jpeg" => Some("jpeg"),
+        "gif" => Some("gif"),
+        "webp" => Some("webp"),
+        "pdf" => Some("pdf"),
+        _ => None,
+    }
+}
+
+pub fn mime_of(ext: &str) -> &'static str {
+    match ext {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+// Feeds the content into the digest, returning its size
+fn hash<R: Read>(ctx: &mut digest::Context, reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 64 * 1024];
+    let mut size = 0;
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(size),
+            n => {
+                ctx.update(&buf[..n]);
+                size += n as u64;
+            },
+        }
+    }
+}
+
+// (size, SHA-256) of the content
+fn digest_of<R: Read>(reader: &mut R) -> io::Result<(u64, Vec<u8>)> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let size = hash(&mut ctx, reader)?;
+    Ok((size, ctx.finish().as_ref().to_vec()))
+}
+
+// What the client declares when starting an upload
+pub struct NewUpload {
+    pub entry: i32,
+    pub ext: String,
+    pub size: u64,
+    pub sha256: String, // Hex
+    pub original: String,
+    pub caption: Option<String>,
+    pub uploader: Option<String>,
+}
+
+// An upload in progress, written into PARTIAL_DIR until complete
+struct Session {
+    entry: i32,
+    name: String, // Name the file is referred to by
+    size: u64,
+    sha256: Vec<u8>,
+    original: String,
+    caption: Option<String>,
+    uploader: Option<String>,
+    file: File,
+    written: u64,
+    digest: digest::Context, // Of the content written so far
+    touched: Instant,
+}
+
+impl Session {
+    fn partial_path(id: &str) -> PathBuf {
+        Path::new(PARTIAL_DIR).join(id)
+    }
+
+    // Type of the content must match the extension, where recognizable
+    fn check_type(&self, id: &str) -> Result<(), StoreError> {
+        let ext = FileName::parse(&self.name).unwrap().ext;
+        if let Some(expected) = claimed(ext) {
+            let mut head = Vec::with_capacity(SNIFF_LEN);
+            File::open(Session::partial_path(id))
+                .and_then(|f| f.take(SNIFF_LEN as u64).read_to_end(&mut head))
+                .map_err(|_| StoreError::SystemError)?;
+            if sniff(&head) != Some(expected) {
+                return Err(StoreError::FileTypeNotAllowed);
+            }
+        }
+        Ok(())
+    }
+
+    // Digest of the first `len` bytes written, hashed again from the file. Only needed when a chunk is resent
+    fn digest_prefix(id: &str, len: u64) -> io::Result<digest::Context> {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        hash(&mut ctx, &mut File::open(Session::partial_path(id))?.take(len))?;
+        Ok(ctx)
+    }
+}
+
+// Upload sessions, which survive the connection so that uploads can be resumed
+pub struct Uploads {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Uploads {
+    pub fn new() -> Uploads {
+        Uploads { sessions: Mutex::new(HashMap::new()) }
+    }
+
+    // Starts a session for the file, returning its id. `used` is the storage already taken by the entry
+    pub fn start(&self, upload: NewUpload, used: u64, policy: &UploadPolicy) -> Result<String, StoreError> {
+        let NewUpload { entry, ext, size, sha256, original, caption, uploader } = upload;
+
+        let name = new_name(entry, &ext)?;
+        let ext = FileName::parse(&name).unwrap().ext.to_owned();
+        if !policy.allowed.iter().any(|a| a.eq_ignore_ascii_case(&ext)) {
+            return Err(StoreError::FileTypeNotAllowed);
+        }
+
+        let sha256 = match from_hex(&sha256) {
+            Some(ref h) if h.len() == digest::SHA256_OUTPUT_LEN => h.clone(),
+            _ => return Err(StoreError::ChecksumMismatch),
+        };
+
+        if size > policy.max_size {
+            return Err(StoreError::FileTooLarge);
+        }
+
+        self.prune(policy.session_timeout);
+        let mut sessions = self.sessions.lock().unwrap();
+
+        // Unfinished uploads count towards the quota as well
+        let reserved: u64 = sessions.values().filter(|s| s.entry == entry).map(|s| s.size).sum();
+        if used + reserved + size > policy.quota {
+            return Err(StoreError::QuotaExceeded);
+        }
+
+        let id = Uuid::new_v4().simple().to_string();
+        fs::create_dir_all(PARTIAL_DIR).map_err(|_| StoreError::SystemError)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(Session::partial_path(&id))
+            .map_err(|_| StoreError::SystemError)?;
+
+        sessions.insert(id.clone(), Session {
+            entry,
+            name,
+            size,
+            sha256,
+            original,
+            caption,
+            uploader,
+            file,
+            written: 0,
+            digest: digest::Context::new(&digest::SHA256),
+            touched: Instant::now(),
+        });
+        Ok(id)
+    }
+
+    // (offset, size) of a session accessible to a connection limited to `limited`
+    pub fn status(&self, id: &str, limited: Option<i32>) -> Result<(u64, u64), StoreError> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = Uploads::access(&sessions, id, limited)?;
+        Ok((session.written, session.size))
+    }
+
+    // Writes a chunk at `offset`, which may rewind to resend unacknowledged data. Returns the new offset
+    pub fn write(&self, id: &str, limited: Option<i32>, offset: u64, data: &[u8]) -> Result<u64, StoreError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        Uploads::access(&sessions, id, limited)?;
+        let session = sessions.get_mut(id).unwrap();
+
+        if offset > session.written {
+            return Err(StoreError::InvalidOffset);
+        }
+
+        if offset + data.len() as u64 > session.size {
+            return Err(StoreError::FileTooLarge);
+        }
+
+        if offset < session.written {
+            session.digest = Session::digest_prefix(id, offset).map_err(|_| StoreError::SystemError)?;
+        }
+
+        session.file.set_len(offset)
+            .and_then(|_| session.file.seek(SeekFrom::Start(offset)))
+            .and_then(|_| session.file.write_all(data))
+            .map_err(|_| StoreError::SystemError)?;
+
+        // Hashed as it arrives, so that finishing needs not to read the file again
+        session.digest.update(data);
+        session.written = offset + data.len() as u64;
+        session.touched = Instant::now();
+        Ok(session.written)
+    }
+
+    // Verifies and moves the file into the blob store, and records it, returning the record.
+    // The session is dropped if the content turns out to be invalid. Renditions are left to the caller
+    pub fn finish(
+        &self,
+        store: &RwLock<Store>,
+        id: &str,
+        limited: Option<i32>,
+        max_pixels: u64) -> Result<FileRecord, StoreError> {
+
+        let mut sessions = self.sessions.lock().unwrap();
+        Uploads::access(&sessions, id, limited)?;
+
+        {
+            let session = &sessions[id];
+            if session.written != session.size {
+                return Err(StoreError::IncompleteUpload);
+            }
+        }
+
+        // Other sessions need not wait for the checks below
+        let session = sessions.remove(id).unwrap();
+        drop(sessions);
+
+        let digest = session.digest.clone().finish();
+        let verified = session.file.sync_all()
+            .map_err(|_| StoreError::SystemError)
+            .and_then(|_| session.check_type(id))
+            .and_then(|_| if digest.as_ref() == &session.sha256[..] {
+                Ok(())
+            } else {
+                Err(StoreError::ChecksumMismatch)
+            });
+
+        let path = Session::partial_path(id);
+        let mime = mime_of(FileName::parse(&session.name).unwrap().ext).to_owned();
+        let verified = verified.and_then(|_| if within_limit(&path, &mime, max_pixels) {
+            Ok(())
+        } else {
+            Err(StoreError::ImageTooLarge)
+        });
+
+        if let Err(e) = verified {
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+
+        let record = FileRecord {
+            entry: session.entry,
+            original: session.original,
+            mime,
+            size: session.size,
+            sha256: to_hex(&session.sha256),
+            uploader: session.uploader,
+            created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            caption: session.caption,
+            name: session.name,
+        };
+
+        // The collector holds the store while removing blobs. Holding it from finding the content
+        // already stored until the reference is counted keeps the existing blob from being collected in between
+        let mut store = store.write().unwrap();
+        store_blob(&path, &record.sha256)?;
+        store.record_file(&record);
+        Ok(record)
+    }
+
+    pub fn cancel(&self, id: &str, limited: Option<i32>) -> Result<(), StoreError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        Uploads::access(&sessions, id, limited)?;
+        sessions.remove(id);
+        let _ = fs::remove_file(Session::partial_path(id));
+        Ok(())
+    }
+
+    // Removes sessions idle for longer than `timeout` seconds, and partial files left by previous runs
+    pub fn prune(&self, timeout: u64) {
+        let timeout = Duration::from_secs(timeout);
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let abandoned: Vec<String> = sessions.iter()
+            .filter(|&(_, s)| s.touched.elapsed() > timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in abandoned {
+            sessions.remove(&id);
+            let _ = fs::remove_file(Session::partial_path(&id));
+        }
+
+        if let Ok(iter) = fs::read_dir(PARTIAL_DIR) {
+            for e in iter.filter_map(Result::ok) {
+                let orphan = e.file_name().into_string().map_or(true, |id| !sessions.contains_key(&id));
+                if orphan {
+                    let _ = fs::remove_file(e.path());
+                }
+            }
+        }
+    }
+
+    fn access<'a>(
+        sessions: &'a HashMap<String, Session>,
+        id: &str,
+        limited: Option<i32>) -> Result<&'a Session, StoreError> {
+
+        let session = sessions.get(id).ok_or(StoreError::NotFound)?;
+        if limited.map_or(false, |e| e != session.entry) {
+            return Err(StoreError::Denied);
+        }
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "936da01f-9abd-4d9d-80c7-02af85c822a8";
+
+    #[test]
+    fn parses_file_names() {
+        let name = format!("42.{}.png", UUID);
+        let f = FileName::parse(&name).unwrap();
+        assert_eq!(f.entry, 42);
+        assert_eq!(f.uuid, UUID);
+        assert_eq!(f.ext, "png");
+    }
+
+    #[test]
+    fn rejects_malformed_file_names() {
+        let bad = [
+            format!("x.{}.png", UUID),
+            format!("42.{}", UUID),
+            format!("42.{}.", UUID),
+            format!("42.{}.tar.gz", UUID),
+            format!("42.{}.png/..", UUID),
+            format!("42.{}.png", UUID.replace("-", "")),
+            "42.not-a-uuid.png".to_owned(),
+            "../../etc/passwd".to_owned(),
+            String::new(),
+        ];
+        for name in &bad {
+            assert!(FileName::parse(name).is_none(), "{:?} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn new_names_parse_back() {
+        let name = new_name(7, ".JPG").unwrap();
+        let f = FileName::parse(&name).unwrap();
+        assert_eq!(f.entry, 7);
+        assert_eq!(f.ext, "jpg");
+
+        assert!(new_name(7, "").is_err());
+        assert!(new_name(7, "a/b").is_err());
+        assert!(new_name(7, "verylongextension").is_err());
+    }
+
+    #[test]
+    fn limits_files_to_their_entry() {
+        let name = format!("42.{}.png", UUID);
+        assert!(permits(&name, None));
+        assert!(permits(&name, Some(42)));
+        assert!(!permits(&name, Some(43)));
+        assert!(!permits("../42.png", None));
+    }
+
+    #[test]
+    fn keeps_blob_paths_in_the_blob_directory() {
+        let sha = "ab".repeat(32);
+        assert_eq!(blob_path(&sha).unwrap(), Path::new(BLOB_DIR).join("ab").join(&sha));
+        assert!(blob_path(&"ab".repeat(31)).is_err());
+        assert!(blob_path(&format!("../{}", "a".repeat(61))).is_err());
+    }
+
+    #[test]
+    fn sniffs_known_signatures() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\x0d"), Some("png"));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("jpeg"));
+        assert_eq!(sniff(b"GIF89a\x01\0\x01\0"), Some("gif"));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBP"), Some("webp"));
+        assert_eq!(sniff(b"%PDF-1.4\n"), Some("pdf"));
+    }
+
+    #[test]
+    fn sniffs_nothing_from_unknown_or_short_content() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"\x89PNG"), None);
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVE"), None);
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEB"), None);
+        assert_eq!(sniff(b"<svg xmlns="), None);
+    }
+
+    #[test]
+    fn extensions_claim_the_sniffed_types() {
+        assert_eq!(claimed("jpg"), claimed("jpeg"));
+        assert_eq!(claimed("png"), sniff(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(claimed("svg"), None);
+        assert_eq!(mime_of("jpg"), "image/jpeg");
+        assert_eq!(mime_of("exe"), "application/octet-stream");
+    }
+}
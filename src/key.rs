@@ -1,21 +1,149 @@
 use byteorder::{ByteOrder, LittleEndian};
+use config::Config;
 use ring::aead;
 use ring::digest;
+use ring::hmac;
+use ring::pbkdf2;
 use ring::error::Unspecified;
 use ring::rand::{SystemRandom, SecureRandom};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de;
 use std;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-// Authentication utilities for a single entry
-pub fn generate_key(target: i32, master: &[u8]) -> Result<String, Unspecified> {
-    // Convert to bytes
-    let mut buf: [u8; 4 + aead::MAX_TAG_LEN] = [0; 4 + aead::MAX_TAG_LEN]; // 4 bytes for input, MAX_LAG_LEN for cap
-    LittleEndian::write_i32(&mut buf, target);
+// Sealed payload: entry (4) + key id (4) + issued (8) + expiry (8) + scope (1)
+const PAYLOAD_LEN: usize = 25;
+// Keys issued before scoping only contain the entry id
+const LEGACY_PAYLOAD_LEN: usize = 4;
+
+const KDF_ITERATIONS: u32 = 100_000;
+const KDF_SALT: &str = "CommRegServer club key";
+
+pub fn to_hex(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len() * 2);
+    for byte in data {
+        write!(&mut result as &mut std::fmt::Write, "{:02x}", byte).unwrap();
+    }
+    result
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len() / 2)
+        .map(|i| u8::from_str_radix(&s[i*2..i*2+2], 16).ok())
+        .collect()
+}
+
+// Permissions granted by a club key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scope(u8);
+
+const SCOPE_NAMES: &[(&str, Scope)] = &[
+    ("edit", Scope::EDIT),
+    ("upload", Scope::UPLOAD),
+    ("deleteFiles", Scope::DELETE_FILES),
+];
+
+impl Scope {
+    pub const EDIT: Scope = Scope(1); // Editing text
+    pub const UPLOAD: Scope = Scope(2); // Uploading files
+    pub const DELETE_FILES: Scope = Scope(4); // Deleting files
+    pub const ALL: Scope = Scope(7);
 
-    // Create a 256-bit hash of the master secret
-    let hash = digest::digest(&digest::SHA256, master);
+    pub fn contains(&self, other: Scope) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(SCOPE_NAMES
+            .iter()
+            .filter(|&&(_, s)| self.contains(s))
+            .map(|&(name, _)| name))
+    }
+}
+
+impl<'de> Deserialize<'de> for Scope {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Scope, D::Error> {
+        let names: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut result = Scope(0);
+        for name in names {
+            match SCOPE_NAMES.iter().find(|&&(n, _)| n == name) {
+                None => return Err(de::Error::custom(format!("Unknown scope: {}", name))),
+                Some(&(_, s)) => result.0 |= s.0,
+            }
+        }
+        Ok(result)
+    }
+}
+
+// Content of a club key, also kept in the store as the record of an issued key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Claims {
+    pub id: u32, // Key id, 0 for legacy keys
+    #[serde(default)]
+    pub ring: u32, // Id of the secret sealing this key, not part of the payload
+    pub entry: i32,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub label: Option<String>, // Holder of the key, e.g. "President", not part of the payload
+    pub issued: u64, // Unix timestamp, s
+    pub expiry: Option<u64>, // Unix timestamp, s
+    pub scope: Scope,
+}
+
+impl Claims {
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.expiry.map_or(false, |e| e <= now)
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        LittleEndian::write_i32(&mut buf[0..4], self.entry);
+        LittleEndian::write_u32(&mut buf[4..8], self.id);
+        LittleEndian::write_u64(&mut buf[8..16], self.issued);
+        LittleEndian::write_u64(&mut buf[16..24], self.expiry.unwrap_or(0));
+        buf[24] = self.scope.0;
+    }
+
+    fn decode(payload: &[u8]) -> Option<Claims> {
+        match payload.len() {
+            LEGACY_PAYLOAD_LEN => Some(Claims {
+                id: 0,
+                ring: 0,
+                entry: LittleEndian::read_i32(payload),
+                label: None,
+                issued: 0,
+                expiry: None,
+                scope: Scope::ALL,
+            }),
+            PAYLOAD_LEN => Some(Claims {
+                id: LittleEndian::read_u32(&payload[4..8]),
+                ring: 0,
+                entry: LittleEndian::read_i32(&payload[0..4]),
+                label: None,
+                issued: LittleEndian::read_u64(&payload[8..16]),
+                expiry: match LittleEndian::read_u64(&payload[16..24]) {
+                    0 => None,
+                    e => Some(e),
+                },
+                scope: Scope(payload[24] & Scope::ALL.0),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn seal(claims: &Claims, key: &[u8]) -> Result<String, Unspecified> {
+    // Convert to bytes
+    let mut buf: [u8; PAYLOAD_LEN + aead::MAX_TAG_LEN] = [0; PAYLOAD_LEN + aead::MAX_TAG_LEN];
+    claims.encode(&mut buf);
 
     // Creating sealing key
-    let sealing_key = aead::SealingKey::new(&aead::AES_256_GCM, hash.as_ref())?;
+    let sealing_key = aead::SealingKey::new(&aead::AES_256_GCM, key)?;
 
     // Generate a 96-bit nonce
     let mut nonce: [u8; 12] = [0; 12];
@@ -28,62 +156,296 @@ pub fn generate_key(target: i32, master: &[u8]) -> Result<String, Unspecified> {
         &[],
         &mut buf,
         aead::MAX_TAG_LEN)?;
-    let mut result = String::with_capacity(12 * 2 + len * 2);
 
-    for byte in &nonce {
-        write!(&mut result as &mut std::fmt::Write, "{:02x}", byte).map_err(|_| Unspecified)?
-    }
-    for byte in &buf[..len] {
-        write!(&mut result as &mut std::fmt::Write, "{:02x}", byte).map_err(|_| Unspecified)?
-    }
-    Ok(result)
+    Ok(to_hex(&nonce) + &to_hex(&buf[..len]))
 }
 
-pub fn try_decrypt_key(key: &str, master: &[u8]) -> Option<i32> {
-    if key.len() % 2 != 0 || key.len() < 12 * 2 {
+fn open(sealed: &str, key: &[u8]) -> Option<Claims> {
+    let mut data = from_hex(sealed)?;
+    if data.len() < 12 {
         // Got no nounce
         return None;
     }
 
-    let data = &key[12*2..];
-    let mut nonce_vec: [u8; 12] = [0; 12];
-    let mut data_vec: Vec<u8> = Vec::with_capacity(data.len() / 2);
+    let mut sealed = data.split_off(12);
+    let nonce = data;
+
+    // Creating opening key
+    let opening_key = aead::OpeningKey::new(&aead::AES_256_GCM, key).ok()?;
+
+    let payload = aead::open_in_place(
+        &opening_key,
+        &nonce,
+        &[],
+        0,
+        &mut sealed).ok()?;
+
+    Claims::decode(payload)
+}
+
+// Sealing keys derived from one master secret
+struct RingKey {
+    id: u32,
+    derived: [u8; 32], // PBKDF2, used for current keys
+    legacy: [u8; 32], // Bare SHA-256, used by keys without a ring id
+}
+
+impl RingKey {
+    fn new(id: u32, secret: &[u8]) -> RingKey {
+        let salt = format!("{} {}", KDF_SALT, id);
+        let mut derived = [0u8; 32];
+        pbkdf2::derive(&digest::SHA256, KDF_ITERATIONS, salt.as_bytes(), secret, &mut derived);
+
+        let mut legacy = [0u8; 32];
+        legacy.copy_from_slice(digest::digest(&digest::SHA256, secret).as_ref());
+
+        RingKey { id, derived, legacy }
+    }
+}
+
+// Authentication utilities for a single entry
+//
+// Keys are written as "<ring id>.<hex>". New keys are always sealed with the current secret,
+// while keys sealed with previous secrets, or written without a ring id, keep verifying
+pub struct KeyRing {
+    current: RingKey,
+    previous: Vec<RingKey>,
+}
 
-    let result: Result<(), std::num::ParseIntError> = do catch {
-        for i in 0..12 {
-            nonce_vec[i] = u8::from_str_radix(&key[i*2..i*2+2], 16)?;
+impl KeyRing {
+    pub fn from_config(config: &Config) -> KeyRing {
+        KeyRing {
+            current: RingKey::new(config.secret_id, config.secret.as_bytes()),
+            previous: config.previous_secrets
+                .iter()
+                .map(|s| RingKey::new(s.id, s.secret.as_bytes()))
+                .collect(),
         }
+    }
+
+    pub fn current_id(&self) -> u32 {
+        self.current.id
+    }
+
+    // Separate from the sealing key, so that tokens and keys can never be mistaken for each other
+    fn preview_key(&self) -> hmac::SigningKey {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(b"preview");
+        ctx.update(&self.current.derived);
+        hmac::SigningKey::new(&digest::SHA256, ctx.finish().as_ref())
+    }
+
+    // Token letting a connection fetch files of hidden or deleted entries over HTTP,
+    // written as "<expiry>.<entry>.<hex>", with the entry empty for administrators
+    pub fn preview_token(&self, limited: Option<i32>, expiry: u64) -> String {
+        let payload = format!("{}.{}", expiry, limited.map_or(String::new(), |e| e.to_string()));
+        let tag = hmac::sign(&self.preview_key(), payload.as_bytes());
+        format!("{}.{}", payload, to_hex(tag.as_ref()))
+    }
 
-        for i in 0..data.len() / 2 {
-            data_vec.push(u8::from_str_radix(&data[i*2..i*2+2], 16)?);
+    // Whether the token is still valid for files of the entry. Tokens only verify under the current secret
+    pub fn verify_preview(&self, token: &str, entry: i32) -> bool {
+        let mut segs = token.rsplitn(2, '.');
+        let (tag, payload) = match (segs.next().and_then(from_hex), segs.next()) {
+            (Some(tag), Some(payload)) => (tag, payload),
+            _ => return false,
+        };
+
+        if hmac::verify_with_own_key(&self.preview_key(), payload.as_bytes(), &tag).is_err() {
+            return false;
         }
-        Ok(())
-    };
 
-    if result.is_err() {
-        return None;
+        let mut fields = payload.splitn(2, '.');
+        let expiry: u64 = match fields.next().and_then(|e| e.parse().ok()) {
+            Some(e) => e,
+            None => return false,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        expiry > now && match fields.next() {
+            Some("") => true,
+            Some(limited) => limited.parse::<i32>().ok() == Some(entry),
+            None => false,
+        }
+    }
+
+    fn keys(&self) -> Vec<&RingKey> {
+        let mut result = Vec::with_capacity(self.previous.len() + 1);
+        result.push(&self.current);
+        result.extend(self.previous.iter());
+        result
     }
 
-    let result: Result<(), Unspecified> = do catch {
-        // Create a 256-bit hash of the master secret
-        let hash = digest::digest(&digest::SHA256, master);
+    pub fn generate_key(&self, claims: &Claims) -> Result<String, Unspecified> {
+        Ok(format!("{}.{}", self.current.id, seal(claims, &self.current.derived)?))
+    }
 
-        // Creating opening key
-        let opening_key = aead::OpeningKey::new(&aead::AES_256_GCM, hash.as_ref())?;
+    pub fn try_decrypt_key(&self, key: &str) -> Option<Claims> {
+        if let Some(pos) = key.find('.') {
+            let id: u32 = key[..pos].parse().ok()?;
+            let ring_key = self.keys().into_iter().find(|k| k.id == id)?;
+            let mut claims = open(&key[pos+1..], &ring_key.derived)?;
+            claims.ring = id;
+            return Some(claims);
+        }
 
+        self.keys().into_iter().filter_map(|k| {
+            open(key, &k.legacy).map(|mut claims| {
+                claims.ring = k.id;
+                claims
+            })
+        }).next()
+    }
+}
 
-        aead::open_in_place(
-            &opening_key,
-            &nonce_vec,
-            &[],
-            0,
-            &mut data_vec)?;
-        Ok(())
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if result.is_err() {
-        return None;
+    fn ring(id: u32, secret: &str) -> KeyRing {
+        KeyRing {
+            current: RingKey::new(id, secret.as_bytes()),
+            previous: Vec::new(),
+        }
+    }
+
+    fn claims(expiry: Option<u64>, scope: Scope) -> Claims {
+        Claims {
+            id: 7,
+            ring: 0,
+            entry: 42,
+            label: None,
+            issued: 1_500_000_000,
+            expiry,
+            scope,
+        }
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        assert_eq!(to_hex(&[0, 15, 16, 255]), "000f10ff");
+        assert_eq!(from_hex("000f10ff"), Some(vec![0, 15, 16, 255]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("é0"), None);
+    }
+
+    #[test]
+    fn claims_round_trip() {
+        let mut buf = [0u8; PAYLOAD_LEN];
+        claims(Some(1_600_000_000), Scope::UPLOAD).encode(&mut buf);
+        let decoded = Claims::decode(&buf).unwrap();
+        assert_eq!(decoded.id, 7);
+        assert_eq!(decoded.entry, 42);
+        assert_eq!(decoded.issued, 1_500_000_000);
+        assert_eq!(decoded.expiry, Some(1_600_000_000));
+        assert_eq!(decoded.scope, Scope::UPLOAD);
+
+        claims(None, Scope::ALL).encode(&mut buf);
+        assert_eq!(Claims::decode(&buf).unwrap().expiry, None);
+    }
+
+    #[test]
+    fn decodes_legacy_payloads() {
+        let mut buf = [0u8; LEGACY_PAYLOAD_LEN];
+        LittleEndian::write_i32(&mut buf, 42);
+        let decoded = Claims::decode(&buf).unwrap();
+        assert_eq!(decoded.id, 0);
+        assert_eq!(decoded.entry, 42);
+        assert_eq!(decoded.expiry, None);
+        assert_eq!(decoded.scope, Scope::ALL);
+
+        assert!(Claims::decode(&[0u8; 5]).is_none());
+        assert!(Claims::decode(&[]).is_none());
+    }
+
+    #[test]
+    fn ignores_unknown_scope_bits() {
+        let mut buf = [0u8; PAYLOAD_LEN];
+        claims(None, Scope::EDIT).encode(&mut buf);
+        buf[24] = 0xff;
+        assert_eq!(Claims::decode(&buf).unwrap().scope, Scope::ALL);
+    }
+
+    #[test]
+    fn keys_open_under_their_ring() {
+        let old = ring(1, "old secret");
+        let key = old.generate_key(&claims(None, Scope::EDIT)).unwrap();
+        assert!(key.starts_with("1."));
+
+        let opened = old.try_decrypt_key(&key).unwrap();
+        assert_eq!(opened.entry, 42);
+        assert_eq!(opened.ring, 1);
+
+        let mut rotated = ring(2, "new secret");
+        assert!(rotated.try_decrypt_key(&key).is_none());
+        rotated.previous.push(RingKey::new(1, b"old secret"));
+        assert_eq!(rotated.try_decrypt_key(&key).unwrap().ring, 1);
+    }
+
+    #[test]
+    fn rejects_tampered_keys() {
+        let keyring = ring(1, "secret");
+        let key = keyring.generate_key(&claims(None, Scope::ALL)).unwrap();
+        let last = key.len() - 1;
+        let flipped = if &key[last..] == "0" { "1" } else { "0" };
+        assert!(keyring.try_decrypt_key(&format!("{}{}", &key[..last], flipped)).is_none());
+        assert!(keyring.try_decrypt_key("1.").is_none());
+        assert!(keyring.try_decrypt_key("x.00").is_none());
+    }
+
+    #[test]
+    fn scopes_serialize_as_names() {
+        assert_eq!(::serde_json::to_string(&Scope::ALL).unwrap(), r#"["edit","upload","deleteFiles"]"#);
+        let scope: Scope = ::serde_json::from_str(r#"["upload","edit"]"#).unwrap();
+        assert_eq!(scope, Scope(3));
+        assert!(::serde_json::from_str::<Scope>(r#"["admin"]"#).is_err());
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
     }
 
-    Some(LittleEndian::read_i32(data_vec.as_slice()))
+    #[test]
+    fn preview_tokens_cover_their_entry() {
+        let keyring = ring(1, "secret");
+        let token = keyring.preview_token(Some(42), now() + 60);
+        assert!(keyring.verify_preview(&token, 42));
+        assert!(!keyring.verify_preview(&token, 43));
+
+        let admin = keyring.preview_token(None, now() + 60);
+        assert!(keyring.verify_preview(&admin, 42));
+        assert!(keyring.verify_preview(&admin, 43));
+    }
+
+    #[test]
+    fn rejects_expired_preview_tokens() {
+        let keyring = ring(1, "secret");
+        assert!(!keyring.verify_preview(&keyring.preview_token(Some(42), now() - 1), 42));
+        assert!(!keyring.verify_preview(&keyring.preview_token(None, 0), 42));
+    }
+
+    #[test]
+    fn rejects_forged_preview_tokens() {
+        let keyring = ring(1, "secret");
+        let expiry = now() + 60;
+        let token = keyring.preview_token(Some(42), expiry);
+
+        // Moving the token to another entry, or making it an administrator's, breaks the tag
+        let tag = token.rsplit('.').next().unwrap();
+        assert!(!keyring.verify_preview(&format!("{}.43.{}", expiry, tag), 43));
+        assert!(!keyring.verify_preview(&format!("{}..{}", expiry, tag), 43));
+        assert!(!keyring.verify_preview(&format!("{}.42.{}", expiry + 3600, tag), 42));
+
+        assert!(!ring(2, "other").verify_preview(&token, 42));
+        assert!(!keyring.verify_preview("", 42));
+        assert!(!keyring.verify_preview(&format!("{}.42", expiry), 42));
+    }
+
+    #[test]
+    fn preview_tokens_are_not_keys() {
+        let keyring = ring(1, "secret");
+        let token = keyring.preview_token(Some(42), now() + 60);
+        assert!(keyring.try_decrypt_key(&token).is_none());
+    }
 }
@@ -8,14 +8,22 @@ extern crate serde;
 extern crate serde_json;
 extern crate serde_yaml;
 extern crate leveldb;
+extern crate db_key;
 extern crate uuid;
 extern crate ring;
 extern crate byteorder;
 extern crate jieba;
+extern crate image;
+extern crate native_tls;
 
 #[macro_use]
 extern crate lazy_static;
 
 pub mod config;
 pub mod store;
+pub mod meta;
+pub mod date;
 pub mod key;
+pub mod account;
+pub mod mail;
+pub mod files;
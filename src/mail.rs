@@ -0,0 +1,370 @@
+use config::{MailConfig, MailTransport, SmtpConfig};
+use key::{KeyRing, Scope};
+use native_tls::{HandshakeError, TlsConnector};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use store::{Store, StoreError, Delivery};
+use uuid::Uuid;
+
+const SMTP_TIMEOUT: u64 = 30; // s
+
+const DEFAULT_SUBJECT: &str = "Login link for {{name}}";
+const DEFAULT_TEMPLATE: &str = "Hello {{name}} / {{name_eng}},
+
+Please use the following link to edit the registration of your club:
+
+{{link}}
+
+The link can be used only once, and expires in {{days}} days.
+";
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(io::Error),
+    Rejected(String), // Reply from the server
+    Tls(String),
+    Insecure, // Credentials are configured, but the server does not offer STARTTLS
+}
+
+impl From<io::Error> for MailError {
+    fn from(e: io::Error) -> MailError {
+        MailError::Io(e)
+    }
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MailError::Io(ref e) => write!(f, "{}", e),
+            MailError::Rejected(ref reply) => write!(f, "Rejected: {}", reply),
+            MailError::Tls(ref e) => write!(f, "TLS: {}", e),
+            MailError::Insecure => write!(f, "STARTTLS not offered"),
+        }
+    }
+}
+
+impl Error for MailError {
+    fn description(&self) -> &str {
+        match *self {
+            MailError::Io(_) => "Cannot reach the mail server",
+            MailError::Rejected(_) => "Message rejected by the mail server",
+            MailError::Tls(_) => "Cannot establish TLS with the mail server",
+            MailError::Insecure => "The mail server does not offer STARTTLS, refusing to send credentials",
+        }
+    }
+}
+
+pub trait Mailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError>;
+}
+
+pub fn mailer(config: &MailConfig) -> Box<Mailer> {
+    match config.transport {
+        MailTransport::Smtp(ref smtp) => Box::new(SmtpMailer {
+            config: smtp.clone(),
+            from: config.from.clone(),
+        }),
+        MailTransport::Maildir(ref dir) => Box::new(MaildirMailer {
+            dir: dir.clone(),
+            from: config.from.clone(),
+        }),
+    }
+}
+
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(data: &[u8]) -> String {
+    let mut result = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_CHARS[(n >> (18 - i * 6)) & 63] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+// Address within "Name <address>"
+fn address(mailbox: &str) -> &str {
+    match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start+1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+// RFC 5322 message, with the UTF-8 body in base64 so that it is safe for any transport
+fn compose(from: &str, to: &str, subject: &str, body: &str) -> String {
+    let encoded = base64(body.as_bytes());
+    let lines: Vec<&str> = encoded.as_bytes()
+        .chunks(76)
+        .map(|l| ::std::str::from_utf8(l).unwrap())
+        .collect();
+
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: =?UTF-8?B?{}?=\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=UTF-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+        from,
+        to,
+        base64(subject.as_bytes()),
+        Uuid::new_v4().hyphenated(),
+        address(from).rsplit('@').next().unwrap_or("localhost"),
+        lines.join("\r\n"))
+}
+
+// SMTP, upgraded with STARTTLS whenever the server offers it. Credentials are only sent over TLS
+pub struct SmtpMailer {
+    config: SmtpConfig,
+    from: String,
+}
+
+struct SmtpSession<S: Read + Write> {
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> SmtpSession<S> {
+    // Reads a (possibly multi-line) reply, expecting the given status class
+    // Returns the text of each line, e.g. the extensions listed in reply to EHLO
+    fn expect(&mut self, class: char) -> Result<Vec<String>, MailError> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.stream.read_line(&mut line)?;
+            if line.len() < 4 || !line.starts_with(class) {
+                return Err(MailError::Rejected(line.trim_right().to_owned()));
+            }
+            lines.push(line.get(4..).unwrap_or("").trim_right().to_owned());
+            if line.as_bytes()[3] != b'-' {
+                return Ok(lines);
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), MailError> {
+        self.stream.get_mut().write_all(data)?;
+        Ok(())
+    }
+
+    fn command(&mut self, cmd: &str, class: char) -> Result<Vec<String>, MailError> {
+        self.write(cmd.as_bytes())?;
+        self.write(b"\r\n")?;
+        self.expect(class)
+    }
+}
+
+// Tries each address of the host in turn, none of them for longer than SMTP_TIMEOUT
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "Mail server not found");
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(SMTP_TIMEOUT)) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+impl SmtpMailer {
+    // Everything after the greeting and EHLO
+    fn transact<S: Read + Write>(&self, session: &mut SmtpSession<S>, to: &str, subject: &str, body: &str)
+        -> Result<(), MailError> {
+
+        if let (&Some(ref username), &Some(ref password)) = (&self.config.username, &self.config.password) {
+            let plain = format!("\0{}\0{}", username, password);
+            session.command(&format!("AUTH PLAIN {}", base64(plain.as_bytes())), '2')?;
+        }
+
+        session.command(&format!("MAIL FROM:<{}>", address(&self.from)), '2')?;
+        session.command(&format!("RCPT TO:<{}>", address(to)), '2')?;
+        session.command("DATA", '3')?;
+
+        // The body is base64, so no line can start with a dot
+        session.write(compose(&self.from, to, subject, body).as_bytes())?;
+        session.command(".", '2')?;
+        session.command("QUIT", '2')?;
+        Ok(())
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        let stream = connect(&self.config.host, self.config.port)?;
+        stream.set_read_timeout(Some(Duration::from_secs(SMTP_TIMEOUT)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(SMTP_TIMEOUT)))?;
+
+        let mut session = SmtpSession { stream: BufReader::new(stream) };
+        session.expect('2')?;
+        let extensions = session.command("EHLO localhost", '2')?;
+
+        if !extensions.iter().any(|e| e.eq_ignore_ascii_case("STARTTLS")) {
+            if self.config.username.is_some() {
+                return Err(MailError::Insecure);
+            }
+            return self.transact(&mut session, to, subject, body);
+        }
+
+        session.command("STARTTLS", '2')?;
+        let connector = TlsConnector::builder()
+            .and_then(|b| b.build())
+            .map_err(|e| MailError::Tls(e.to_string()))?;
+        let stream = connector
+            .connect(&self.config.host, session.stream.into_inner())
+            .map_err(|e| match e {
+                HandshakeError::Failure(e) => MailError::Tls(e.to_string()),
+                HandshakeError::Interrupted(_) => MailError::Tls("Handshake timed out".to_owned()),
+            })?;
+
+        // Extensions are announced anew once the connection is secure
+        let mut session = SmtpSession { stream: BufReader::new(stream) };
+        session.command("EHLO localhost", '2')?;
+        self.transact(&mut session, to, subject, body)
+    }
+}
+
+// Writes each message into a maildir instead of sending it
+pub struct MaildirMailer {
+    dir: String,
+    from: String,
+}
+
+impl Mailer for MaildirMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        let base = Path::new(&self.dir);
+        for sub in &["tmp", "new", "cur"] {
+            fs::create_dir_all(base.join(sub))?;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let name = format!("{}.{}.crs", now, Uuid::new_v4().simple());
+
+        // Delivered atomically by moving from tmp into new
+        let mut file = File::create(base.join("tmp").join(&name))?;
+        file.write_all(compose(&self.from, to, subject, body).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(base.join("tmp").join(&name), base.join("new").join(&name))?;
+        Ok(())
+    }
+}
+
+fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter().fold(template.to_owned(), |result, &(name, value)| {
+        result.replace(&format!("{{{{{}}}}}", name), value)
+    })
+}
+
+// A message carrying a one-time login link, ready to be sent
+pub struct Outgoing {
+    id: String, // Of the delivery
+    entry: i32,
+    key: u32,
+    to: String,
+    subject: String,
+    body: String,
+}
+
+// Issues a key for the entry, and renders the message delivering it
+// Sending is left to the caller, so that the store needs not to be locked meanwhile
+pub fn prepare(
+    store: &mut Store,
+    keyring: &KeyRing,
+    config: &MailConfig,
+    entry: i32,
+    label: Option<String>,
+    expiry: Option<u64>,
+    scope: Scope) -> Result<Outgoing, StoreError> {
+
+    let e = store.fetch(entry).ok_or(StoreError::NotFound)?;
+
+    let to = match e.contact() {
+        Some(c) if !c.is_empty() => c.to_owned(),
+        _ => return Err(StoreError::NoContact),
+    };
+
+    let template = match config.template {
+        None => DEFAULT_TEMPLATE.to_owned(),
+        Some(ref path) => {
+            let mut content = String::new();
+            File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut content))
+                .map_err(|_| StoreError::SystemError)?;
+            content
+        },
+    };
+
+    let claims = store.issue_key(entry, label, expiry, scope, keyring.current_id())?;
+    let token = store.create_redeem(&claims, config.link_validity)?;
+    let link = format!("{}/#redeem={}", config.base_url, token);
+    let days = (config.link_validity / (24 * 3600)).to_string();
+
+    let vars = [
+        ("name", e.name()),
+        ("name_eng", e.name_eng()),
+        ("link", link.as_str()),
+        ("days", days.as_str()),
+    ];
+
+    Ok(Outgoing {
+        id: Uuid::new_v4().simple().to_string(),
+        entry,
+        key: claims.id,
+        to,
+        subject: render(config.subject.as_ref().map_or(DEFAULT_SUBJECT, String::as_str), &vars),
+        body: render(&template, &vars),
+    })
+}
+
+// Delivery of a message that has not been sent yet
+pub fn pending(outgoing: &Outgoing) -> Delivery {
+    Delivery {
+        id: outgoing.id.clone(),
+        entry: outgoing.entry,
+        key: outgoing.key,
+        to: outgoing.to.clone(),
+        time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        ok: false,
+        pending: true,
+        error: None,
+    }
+}
+
+pub fn deliver(mailer: &Mailer, outgoing: &Outgoing) -> Delivery {
+    let result = mailer.send(&outgoing.to, &outgoing.subject, &outgoing.body);
+    Delivery {
+        ok: result.is_ok(),
+        pending: false,
+        error: result.err().map(|e| e.to_string()),
+        ..pending(outgoing)
+    }
+}
+
+// Delivers the message on a worker thread, so that a slow mail server holds up no connection
+// `done` is called once the delivery is recorded
+pub fn dispatch<F>(store: &'static RwLock<Store>, config: &'static MailConfig, outgoing: Outgoing, done: F)
+    where F: FnOnce(&Delivery) + Send + 'static {
+
+    store.write().unwrap().record_delivery(&pending(&outgoing));
+    thread::spawn(move || {
+        let delivery = deliver(&*mailer(config), &outgoing);
+        complete(&mut *store.write().unwrap(), &delivery);
+        done(&delivery);
+    });
+}
+
+// Records the delivery, revoking the key if it never reached the club
+pub fn complete(store: &mut Store, delivery: &Delivery) {
+    if !delivery.ok {
+        store.revoke_key(delivery.key, delivery.entry).unwrap();
+    }
+    store.record_delivery(delivery);
+}
@@ -28,26 +28,40 @@ extern crate serde_yaml;
 extern crate ctrlc;
 
 extern crate leveldb;
+extern crate db_key;
 
 extern crate uuid;
 extern crate ring;
 extern crate byteorder;
 
 extern crate jieba;
+extern crate image;
+extern crate native_tls;
 
 mod store;
+mod meta;
+mod date;
 mod query;
 mod admin;
 mod config;
 mod key;
+mod account;
+mod ratelimit;
+mod mail;
+mod files;
+mod serve;
 
 use rocket::Rocket;
+use rocket::http::{ContentType, Cookies};
 use rocket::response::NamedFile;
 use rocket_contrib::Json;
 use rocket::config::Environment;
 use store::Store;
+use std::fs::File;
 use std::sync::*;
 use config::Config;
+use key::KeyRing;
+use serve::Served;
 use ws::WebSocket;
 
 const PING_INTERVAL: u64 = 1; // s
@@ -55,6 +69,7 @@ const PING_INTERVAL: u64 = 1; // s
 lazy_static! {
     pub static ref STORE: RwLock<Store> = RwLock::new(Store::new());
     pub static ref CONFIG: Config = Config::load();
+    pub static ref KEYRING: KeyRing = KeyRing::from_config(&CONFIG);
     pub static ref PING_PAYLOAD: Vec<u8> = vec![97];
 }
 
@@ -68,11 +83,95 @@ fn serve_index() -> Option<NamedFile> {
     NamedFile::open(std::path::Path::new("static/index.html")).ok()
 }
 
+// Holds the token for files of hidden or deleted entries. Kept out of URLs, which end up in logs and Referer
+const PREVIEW_COOKIE: &str = "preview";
+
+#[derive(FromForm)]
+struct FileQuery {
+    size: Option<String>, // One of files::RENDITIONS
+}
+
+// Uploaded files resolved to the blob holding their content, or to one of its renditions
+fn open_file(name: &str, size: Option<String>, cookies: &Cookies) -> Option<Served> {
+    let preview = cookies.get(PREVIEW_COOKIE).map(|c| c.value().to_owned());
+
+    let (record, public) = {
+        let store = STORE.read().unwrap();
+        let record = store.file(name)?;
+        let public = store.fetch(record.entry).map_or(false, |e| e.is_public());
+        (record, public)
+    };
+
+    if !public && !preview.map_or(false, |t| KEYRING.verify_preview(&t, record.entry)) {
+        return None;
+    }
+
+    let mut resolved = (files::blob_path(&record.sha256).ok()?, record.mime.clone(), record.sha256.clone());
+    if let Some(size) = size {
+        let path = files::rendition_path(&record.sha256, &size).ok()?;
+        // Images already small enough have no renditions
+        if path.exists() {
+            resolved = (path, "image/png".to_owned(), format!("{}.{}", record.sha256, size));
+        }
+    }
+    let (path, mime, tag) = resolved;
+
+    let file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+
+    // Anything but images is downloaded, so that uploads are never rendered as pages. SVG may carry scripts
+    let download = if mime.starts_with("image/") && mime != "image/svg+xml" {
+        None
+    } else {
+        let safe: String = record.original.chars()
+            .filter(|c| c.is_ascii() && !c.is_ascii_control() && *c != '"' && *c != '\\')
+            .collect();
+        Some(if safe.is_empty() { record.name.clone() } else { safe })
+    };
+
+    Some(Served {
+        file,
+        size: len,
+        content_type: ContentType::parse_flexible(&mime).unwrap_or(ContentType::Binary),
+        etag: format!("\"{}\"", tag),
+        modified: record.created,
+        public,
+        download,
+    })
+}
+
+#[get("/files/<name>", rank=2)]
+fn serve_file(name: String, cookies: Cookies) -> Option<Served> {
+    open_file(&name, None, &cookies)
+}
+
+#[get("/files/<name>?<query>")]
+fn serve_file_query(name: String, query: FileQuery, cookies: Cookies) -> Option<Served> {
+    open_file(&name, query.size, &cookies)
+}
+
+// Where uploaded files used to be served from
+#[get("/store/<name>")]
+fn serve_store(name: String, cookies: Cookies) -> Option<Served> {
+    open_file(&name, None, &cookies)
+}
+
 #[get("/config")]
 fn serve_config() -> Json<Config> {
     Json(CONFIG.clone())
 }
 
+// Exchanges a one-time link for the club key it refers to
+#[post("/redeem/<token>")]
+fn redeem(token: String) -> Option<Json<serde_json::Value>> {
+    let claims = STORE.write().unwrap().redeem(&token)?;
+    let key = KEYRING.generate_key(&claims).ok()?;
+    Some(Json(json!({
+        "key": key,
+        "entry": claims.entry,
+    })))
+}
+
 fn boot_web() {
     let config = rocket::Config::build(Environment::Staging)
         .address(CONFIG.web.host.clone())
@@ -82,7 +181,9 @@ fn boot_web() {
     std::thread::spawn(move || {
         Rocket::custom(config, true)
             .mount("/query", query::routes())
-            .mount("/", routes![serve_static, serve_index, serve_config])
+            .mount("/", routes![
+                serve_static, serve_index, serve_file, serve_file_query, serve_store, serve_config, redeem,
+            ])
             .manage(&*STORE)
             .launch();
     });
@@ -91,7 +192,7 @@ fn boot_web() {
 fn boot_ws() {
     std::thread::spawn(|| {
         let server = WebSocket::new(|sender|
-                                    admin::Handler::new(sender, &STORE, &CONFIG)).unwrap();
+                                    admin::Handler::new(sender, &STORE, &CONFIG, &KEYRING)).unwrap();
 
         let broadcaster = server.broadcaster();
         // Pinging
@@ -100,6 +201,9 @@ fn boot_ws() {
                 std::thread::sleep(std::time::Duration::from_secs(PING_INTERVAL));
                 let _result = broadcaster.ping(PING_PAYLOAD.clone());
                 // Silently ignores
+
+                admin::prune_uploads(&CONFIG);
+                admin::collect_orphans(&STORE, &CONFIG);
             }
         });
 
@@ -116,6 +220,9 @@ fn main() {
 
     let (tx, rx) = std::sync::mpsc::channel();
 
+    // Files uploaded before storage was content addressed
+    files::migrate(&mut STORE.write().unwrap(), CONFIG.upload.max_pixels).expect("Cannot migrate uploaded files.");
+
     boot_ws();
     boot_web();
 
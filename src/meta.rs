@@ -0,0 +1,116 @@
+use db_key::Key;
+use leveldb::batch::{Batch, Writebatch};
+use leveldb::database::Database;
+use leveldb::iterator::{Iterable, LevelDBIterator};
+use leveldb::kv::KV;
+use leveldb::options::*;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use std::path::Path;
+
+// Auxiliary storage, partitioned into keyspaces.
+// Every key is stored as "<space>:<key>", so a keyspace is a contiguous range in LevelDB
+pub struct MetaKey(Vec<u8>);
+
+impl MetaKey {
+    fn new(space: &str, key: &str) -> MetaKey {
+        MetaKey(format!("{}:{}", space, key).into_bytes())
+    }
+
+    fn within(&self, space: &str) -> Option<&str> {
+        let prefix = space.as_bytes();
+        if self.0.len() > prefix.len()
+            && self.0.starts_with(prefix)
+            && self.0[prefix.len()] == b':' {
+            ::std::str::from_utf8(&self.0[prefix.len() + 1..]).ok()
+        } else {
+            None
+        }
+    }
+}
+
+impl Key for MetaKey {
+    fn from_u8(key: &[u8]) -> MetaKey {
+        MetaKey(key.to_vec())
+    }
+
+    fn as_slice<T, F: Fn(&[u8]) -> T>(&self, f: F) -> T {
+        f(&self.0)
+    }
+}
+
+pub struct MetaBatch {
+    inner: Writebatch<MetaKey>,
+}
+
+impl MetaBatch {
+    pub fn new() -> MetaBatch {
+        MetaBatch { inner: Writebatch::new() }
+    }
+
+    pub fn put<T: Serialize>(&mut self, space: &str, key: &str, value: &T) {
+        let content = serde_json::to_vec(value).unwrap();
+        self.inner.put(MetaKey::new(space, key), &content);
+    }
+
+    pub fn delete(&mut self, space: &str, key: &str) {
+        self.inner.delete(MetaKey::new(space, key));
+    }
+}
+
+pub struct Meta {
+    db: Database<MetaKey>,
+}
+
+impl Meta {
+    pub fn open(path: &Path) -> Meta {
+        let mut dbopt = Options::new();
+        dbopt.create_if_missing = true;
+        Meta { db: Database::open(path, dbopt).unwrap() }
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, space: &str, key: &str) -> Option<T> {
+        self.db.get(ReadOptions::new(), MetaKey::new(space, key))
+            .unwrap()
+            .map(|v| serde_json::from_slice(&v).unwrap())
+    }
+
+    pub fn put<T: Serialize>(&self, space: &str, key: &str, value: &T) {
+        let content = serde_json::to_vec(value).unwrap();
+        self.db.put(WriteOptions::new(), MetaKey::new(space, key), &content).unwrap();
+    }
+
+    pub fn delete(&self, space: &str, key: &str) {
+        self.db.delete(WriteOptions::new(), MetaKey::new(space, key)).unwrap();
+    }
+
+    pub fn write(&self, batch: MetaBatch) {
+        self.db.write(WriteOptions::new(), &batch.inner).unwrap();
+    }
+
+    // Lists all (key, value) pairs within a keyspace, ordered by key
+    pub fn scan<T: DeserializeOwned>(&self, space: &str) -> Vec<(String, T)> {
+        self.scan_from(space, "", usize::max_value())
+    }
+
+    // Lists at most `limit` pairs within a keyspace, with keys no less than `from`
+    pub fn scan_from<T: DeserializeOwned>(&self, space: &str, from: &str, limit: usize) -> Vec<(String, T)> {
+        let start = MetaKey::new(space, from);
+        self.db.iter(ReadOptions::new())
+            .from(&start)
+            .map(|(k, v)| (k.within(space).map(str::to_owned), v))
+            .take_while(|&(ref k, _)| k.is_some())
+            .take(limit)
+            .map(|(k, v)| (k.unwrap(), serde_json::from_slice(&v).unwrap()))
+            .collect()
+    }
+
+    pub fn clear(&self, space: &str) {
+        let mut batch = MetaBatch::new();
+        for (k, _) in self.scan::<serde_json::Value>(space) {
+            batch.delete(space, &k);
+        }
+        self.write(batch);
+    }
+}
@@ -6,12 +6,14 @@ use store::Store;
 use store::*;
 use store::Availability::*;
 use std::sync::*;
-use std::str::{Split, Utf8Error};
+use std::str::{FromStr, Split, Utf8Error};
 
 impl<'a> FromParam<'a> for Availability {
     type Error = &'a RawStr;
     fn from_param(param: &'a RawStr) -> Result<Availability, Self::Error> {
-        if param == "available" {
+        if param == "all" {
+            Ok(All)
+        } else if param == "available" {
             Ok(Available)
         } else if param == "disbanded" {
             Ok(Disbanded)
@@ -21,23 +23,81 @@ impl<'a> FromParam<'a> for Availability {
     }
 }
 
+// Taken as strings, since an Option of the parsed type would silently drop malformed values
+#[derive(FromForm)]
+struct LifecycleQuery {
+    founded_from: Option<String>, // YYYY-MM-DD
+    founded_to: Option<String>,
+    active_in: Option<String>, // Academic year
+    disbanded_after: Option<String>,
+}
+
+// Parses an optional parameter, answering malformed values with 400
+fn strict<T: FromStr>(value: Option<String>) -> Result<Option<T>, Status> {
+    match value {
+        None => Ok(None),
+        Some(v) => v.parse().map(Some).map_err(|_| Status::BadRequest),
+    }
+}
+
+impl LifecycleQuery {
+    fn get(self) -> Result<Lifecycle, Status> {
+        let active_during = match strict(self.active_in)? {
+            None => None,
+            Some(year) => Some(Lifecycle::academic_year(year).ok_or(Status::BadRequest)?),
+        };
+
+        Ok(Lifecycle {
+            founded_from: strict(self.founded_from)?,
+            founded_to: strict(self.founded_to)?,
+            active_during,
+            disbanded_after: strict(self.disbanded_after)?,
+        })
+    }
+}
+
+// Strips private fields before entries are published
+fn public(entries: Vec<Entry>) -> Json<Vec<Entry>> {
+    Json(entries.into_iter().map(Entry::public).collect())
+}
+
 #[get("/<avail>/<search>", rank=2)]
 fn list(store: State<&RwLock<Store>>, avail: Availability, search: &RawStr) -> Result<Json<Vec<Entry>>, Utf8Error> {
-    Ok(Json(store.read()
+    Ok(public(store.read()
+        .unwrap()
+        .filter(Some(avail), Some(search.url_decode()?.split(' ')), None)))
+}
+
+#[get("/<avail>/<search>?<lifecycle>", rank=1)]
+fn list_lifecycle(store: State<&RwLock<Store>>, avail: Availability, search: &RawStr, lifecycle: LifecycleQuery) -> Result<Json<Vec<Entry>>, Status> {
+    let lifecycle = lifecycle.get()?;
+    let search = search.url_decode().map_err(|_| Status::BadRequest)?;
+    Ok(public(store.read()
         .unwrap()
-        .filter(Some(avail), Some(search.url_decode()?.split(' ')))))
+        .filter(Some(avail), Some(search.split(' ')), Some(&lifecycle))))
 }
 
 #[get("/<avail>", rank=2)]
 fn list_all(store: State<&RwLock<Store>>, avail: Availability) -> Json<Vec<Entry>> {
-    Json(store.read().unwrap().filter::<Split<&str>>(Some(avail), None))
+    public(store.read().unwrap().filter::<Split<&str>>(Some(avail), None, None))
+}
+
+#[get("/<avail>?<lifecycle>", rank=1)]
+fn list_all_lifecycle(store: State<&RwLock<Store>>, avail: Availability, lifecycle: LifecycleQuery) -> Result<Json<Vec<Entry>>, Status> {
+    let lifecycle = lifecycle.get()?;
+    Ok(public(store.read().unwrap().filter::<Split<&str>>(Some(avail), None, Some(&lifecycle))))
 }
 
+#[get("/categories")]
+fn categories(store: State<&RwLock<Store>>) -> Json<Vec<CategoryCount>> {
+    Json(store.read().unwrap().categories())
+}
+
 #[get("/fetch/<id>")]
 fn fetch(store: State<&RwLock<Store>>, id: i32) -> Option<Json<Entry>> {
-    store.read().unwrap().fetch(id).map(Json)
+    store.read().unwrap().fetch(id).map(|e| Json(e.public()))
 }
 
 pub fn routes() -> Vec<Route> {
-    routes![list, list_all, fetch]
+    routes![list, list_lifecycle, list_all, list_all_lifecycle, categories, fetch]
 }
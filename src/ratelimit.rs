@@ -0,0 +1,179 @@
+use config::RateLimitConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Prune idle addresses once this many are tracked
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Default)]
+struct Attempts {
+    connections: Vec<Instant>,
+    failures: Vec<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    fn forget(&mut self, now: Instant, window: Duration) {
+        self.connections.retain(|t| now.duration_since(*t) < window);
+        self.failures.retain(|t| now.duration_since(*t) < window);
+        if self.locked_until.map_or(false, |t| t <= now) {
+            self.locked_until = None;
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.connections.is_empty() && self.failures.is_empty() && self.locked_until.is_none()
+    }
+}
+
+// Address of the client, as seen by the nearest hop that is not a trusted proxy
+// Every trusted proxy appends the address it received the request from to X-Forwarded-For,
+// so the header is read from the right, and anything left of the first untrusted hop is ignored
+pub fn client_addr(peer: Option<IpAddr>, forwarded: Option<&str>, trusted: &[IpAddr]) -> Option<IpAddr> {
+    let mut addr = peer?;
+    let mut hops = forwarded.unwrap_or("").rsplit(',');
+    while trusted.contains(&addr) {
+        match hops.next().and_then(|h| h.trim().parse().ok()) {
+            Some(hop) => addr = hop,
+            None => break,
+        }
+    }
+    Some(addr)
+}
+
+// Tracks connections and failed authentications per client address
+pub struct Limiter {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter { attempts: Mutex::new(HashMap::new()) }
+    }
+
+    // Records a new connection, returns false if the address exceeded its allowance or is locked out
+    pub fn connect(&self, addr: &str, config: &RateLimitConfig) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(config.window);
+        let mut attempts = self.attempts.lock().unwrap();
+
+        if attempts.len() >= PRUNE_THRESHOLD {
+            attempts.retain(|_, a| {
+                a.forget(now, window);
+                !a.is_idle()
+            });
+        }
+
+        let entry = attempts.entry(addr.to_owned()).or_insert_with(Attempts::default);
+        entry.forget(now, window);
+        if entry.locked_until.is_some() || entry.connections.len() >= config.connections as usize {
+            return false;
+        }
+        entry.connections.push(now);
+        true
+    }
+
+    pub fn is_locked(&self, addr: &str) -> bool {
+        let now = Instant::now();
+        self.attempts.lock().unwrap()
+            .get(addr)
+            .and_then(|a| a.locked_until)
+            .map_or(false, |t| t > now)
+    }
+
+    // Records a failed authentication, locking the address out once it has failed too often
+    pub fn fail(&self, addr: &str, config: &RateLimitConfig) {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        let entry = attempts.entry(addr.to_owned()).or_insert_with(Attempts::default);
+        entry.forget(now, Duration::from_secs(config.window));
+        entry.failures.push(now);
+        if entry.failures.len() >= config.failures as usize {
+            entry.failures.clear();
+            entry.locked_until = Some(now + Duration::from_secs(config.lockout));
+        }
+    }
+
+    pub fn succeed(&self, addr: &str) {
+        if let Some(entry) = self.attempts.lock().unwrap().get_mut(addr) {
+            entry.failures.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(connections: u32, failures: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            connections,
+            failures,
+            window: 300,
+            lockout: 900,
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn limits_connections_per_address() {
+        let limiter = Limiter::new();
+        let config = config(2, 5);
+        assert!(limiter.connect("10.0.0.1", &config));
+        assert!(limiter.connect("10.0.0.1", &config));
+        assert!(!limiter.connect("10.0.0.1", &config));
+        assert!(limiter.connect("10.0.0.2", &config));
+    }
+
+    #[test]
+    fn locks_out_after_failures() {
+        let limiter = Limiter::new();
+        let config = config(30, 3);
+        limiter.fail("10.0.0.1", &config);
+        limiter.fail("10.0.0.1", &config);
+        assert!(!limiter.is_locked("10.0.0.1"));
+
+        limiter.fail("10.0.0.1", &config);
+        assert!(limiter.is_locked("10.0.0.1"));
+        assert!(!limiter.connect("10.0.0.1", &config));
+        assert!(!limiter.is_locked("10.0.0.2"));
+    }
+
+    #[test]
+    fn success_clears_failures() {
+        let limiter = Limiter::new();
+        let config = config(30, 3);
+        limiter.fail("10.0.0.1", &config);
+        limiter.fail("10.0.0.1", &config);
+        limiter.succeed("10.0.0.1");
+        limiter.fail("10.0.0.1", &config);
+        assert!(!limiter.is_locked("10.0.0.1"));
+    }
+
+    #[test]
+    fn ignores_forwarded_without_trusted_proxies() {
+        let peer = Some(ip("203.0.113.5"));
+        assert_eq!(client_addr(peer, Some("198.51.100.1"), &[]), peer);
+        assert_eq!(client_addr(None, Some("198.51.100.1"), &[]), None);
+    }
+
+    #[test]
+    fn takes_the_hop_appended_by_trusted_proxies() {
+        let trusted = [ip("127.0.0.1"), ip("10.0.0.2")];
+        let peer = Some(ip("127.0.0.1"));
+
+        // Spoofed entries left of the real client are ignored
+        let forwarded = Some("1.2.3.4, 198.51.100.7, 10.0.0.2");
+        assert_eq!(client_addr(peer, forwarded, &trusted), Some(ip("198.51.100.7")));
+        assert_eq!(client_addr(peer, Some("198.51.100.7"), &trusted), Some(ip("198.51.100.7")));
+
+        // Falls back to the last trusted hop when the header is missing or unreadable
+        assert_eq!(client_addr(peer, None, &trusted), peer);
+        assert_eq!(client_addr(peer, Some("unknown"), &trusted), peer);
+    }
+}
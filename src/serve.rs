@@ -0,0 +1,225 @@
+use date::http_date;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::fs::File;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+// Names never change their content, but entries may be hidden or deleted at any time,
+// so caches revalidate shortly, which is cheap with the ETag
+const PUBLIC_CACHE: &str = "public, max-age=60, must-revalidate";
+const PRIVATE_CACHE: &str = "private, max-age=60, must-revalidate";
+
+// An uploaded file, answered with validators and byte ranges
+pub struct Served {
+    pub file: File,
+    pub size: u64, // Bytes
+    pub content_type: ContentType,
+    pub etag: String, // Quoted
+    pub modified: u64, // Unix timestamp, s
+    pub public: bool, // Whether shared caches may keep it
+    pub download: Option<String>, // Name to save the file as, instead of displaying it
+}
+
+// Part of a file, read and seeked as if it were the whole, so that a range is streamed with its length
+struct Slice {
+    file: File,
+    start: u64,
+    len: u64,
+    pos: u64, // Relative to start
+}
+
+impl Slice {
+    fn new(mut file: File, start: u64, len: u64) -> io::Result<Slice> {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Slice { file, start, len, pos: 0 })
+    }
+}
+
+impl Read for Slice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let limit = cmp::min(buf.len() as u64, remaining) as usize;
+        let n = self.file.read(&mut buf[..limit])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Slice {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let pos = match to {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(d) => self.len as i64 + d,
+            SeekFrom::Current(d) => self.pos as i64 + d,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seeking before the start"));
+        }
+
+        self.file.seek(SeekFrom::Start(self.start + pos as u64))?;
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+// A single range of "bytes=a-b", "bytes=a-" or "bytes=-n", as inclusive bounds.
+// Returns None for anything else, including b < a, which is answered with the whole file, and Err if unsatisfiable
+fn parse_range(header: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim();
+    if !spec.starts_with("bytes=") || spec.contains(',') {
+        return None;
+    }
+
+    let mut bounds = spec["bytes=".len()..].splitn(2, '-');
+    let (start, end) = (bounds.next()?.trim(), bounds.next()?.trim());
+
+    let range = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return Some(Err(()));
+        }
+        (size.saturating_sub(suffix), size.saturating_sub(1))
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            size.saturating_sub(1)
+        } else {
+            let end: u64 = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            cmp::min(end, size.saturating_sub(1))
+        };
+        (start, end)
+    };
+
+    if range.0 >= size || range.0 > range.1 {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+impl<'r> Responder<'r> for Served {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let modified = http_date(self.modified);
+        let mut builder = Response::build();
+        builder
+            .raw_header("ETag", self.etag.clone())
+            .raw_header("Last-Modified", modified.clone())
+            .raw_header("Cache-Control", if self.public { PUBLIC_CACHE } else { PRIVATE_CACHE })
+            .raw_header("Accept-Ranges", "bytes")
+            .raw_header("X-Content-Type-Options", "nosniff");
+
+        if let Some(ref name) = self.download {
+            builder.raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", name));
+        }
+
+        let headers = req.headers();
+        let not_modified = match headers.get_one("If-None-Match") {
+            Some(tags) => tags.split(',').any(|t| t.trim() == self.etag || t.trim() == "*"),
+            None => headers.get_one("If-Modified-Since") == Some(modified.as_str()),
+        };
+        if not_modified {
+            return builder.status(Status::NotModified).ok();
+        }
+
+        // A range of a different version is useless, so the whole file is sent instead
+        let fresh = headers.get_one("If-Range").map_or(true, |v| v == self.etag || v == modified);
+        let range = headers.get_one("Range")
+            .filter(|_| fresh)
+            .and_then(|r| parse_range(r, self.size));
+
+        match range {
+            None => builder
+                .header(self.content_type)
+                .sized_body(self.file)
+                .ok(),
+            Some(Err(())) => builder
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", self.size))
+                .ok(),
+            Some(Ok((start, end))) => {
+                let slice = Slice::new(self.file, start, end - start + 1)
+                    .map_err(|_| Status::InternalServerError)?;
+
+                builder
+                    .status(Status::PartialContent)
+                    .header(self.content_type)
+                    .raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, self.size))
+                    .sized_body(slice)
+                    .ok()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-0", 10), Some(Ok((0, 0))));
+        assert_eq!(parse_range("bytes=2-5", 10), Some(Ok((2, 5))));
+        assert_eq!(parse_range("bytes=2-", 10), Some(Ok((2, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(parse_range(" bytes=2-5 ", 10), Some(Ok((2, 5))));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_file() {
+        assert_eq!(parse_range("bytes=5-100", 10), Some(Ok((5, 9))));
+        assert_eq!(parse_range("bytes=-100", 10), Some(Ok((0, 9))));
+    }
+
+    #[test]
+    fn refuses_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=-0", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=100-200", 10), Some(Err(())));
+    }
+
+    #[test]
+    fn refuses_every_range_of_empty_files() {
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-0", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=-1", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn ignores_other_ranges() {
+        assert_eq!(parse_range("bytes=5-2", 10), None);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("items=0-1", 10), None);
+        assert_eq!(parse_range("bytes=a-1", 10), None);
+        assert_eq!(parse_range("bytes=-", 10), None);
+        assert_eq!(parse_range("bytes=5", 10), None);
+        assert_eq!(parse_range("", 10), None);
+    }
+
+    #[test]
+    fn slices_read_and_seek_within_their_range() {
+        let path = env::temp_dir().join(format!("serve-slice-{}", ::std::process::id()));
+        File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+
+        let mut slice = Slice::new(File::open(&path).unwrap(), 2, 5).unwrap();
+        let mut content = String::new();
+        slice.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "23456");
+
+        assert_eq!(slice.seek(SeekFrom::End(0)).unwrap(), 5);
+        assert_eq!(slice.seek(SeekFrom::Start(1)).unwrap(), 1);
+        content.clear();
+        slice.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "3456");
+        assert!(slice.seek(SeekFrom::Current(-10)).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
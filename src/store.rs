@@ -1,3 +1,4 @@
+use leveldb::batch::{Batch, Writebatch};
 use leveldb::database::Database;
 use leveldb::iterator::Iterable;
 use leveldb::kv::KV;
@@ -11,6 +12,12 @@ use std::fs::File;
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 use jieba::Jieba;
+use date;
+use date::{Date, DateError};
+use meta::{Meta, MetaBatch};
+use account::{Account, Role};
+use key::{Claims, Scope, to_hex};
+use ring::rand::{SystemRandom, SecureRandom};
 
 fn get_false() -> bool {
     false
@@ -20,15 +27,97 @@ fn is_false(b: &bool) -> bool {
     return !b;
 }
 
+// Keyspaces of the persisted inverted index
+const INDEX_SPACE: &str = "index";
+const INDEXED_SPACE: &str = "indexed";
+
 lazy_static! {
     pub static ref JIEBA: Jieba = Jieba::new(Path::new("./deps/jieba/lib/dict")).unwrap();
 }
 
 pub enum Availability {
+    All,
     Available,
     Disbanded,
 }
 
+const CATEGORY_SPACE: &str = "category";
+const ACCOUNT_SPACE: &str = "account";
+const KEY_SPACE: &str = "key";
+const REVOKED_SPACE: &str = "revoked";
+const AUDIT_SPACE: &str = "audit";
+const COUNTER_SPACE: &str = "counter";
+const REDEEM_SPACE: &str = "redeem";
+const DELIVERY_SPACE: &str = "delivery";
+const FILE_SPACE: &str = "file";
+const BLOB_SPACE: &str = "blob";
+const ORPHAN_SPACE: &str = "orphan";
+const TAG_SPACE: &str = "tag"; // Controlled vocabulary
+
+// Color of categories found in existing entries, which are added to the registry as they are
+const UNSORTED_COLOR: &str = "#9e9e9e";
+
+// Filters on the lifecycle of entries, all bounds are inclusive
+#[derive(Default)]
+pub struct Lifecycle {
+    pub founded_from: Option<Date>,
+    pub founded_to: Option<Date>,
+    pub active_during: Option<(Date, Date)>,
+    pub disbanded_after: Option<Date>,
+}
+
+impl Lifecycle {
+    // Academic years start on September 1st. None if the year ends out of range
+    pub fn academic_year(year: i32) -> Option<(Date, Date)> {
+        let next = year.checked_add(1)?;
+        Some((Date::new(year, 9, 1).ok()?, Date::new(next, 8, 31).ok()?))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.founded_from.is_none()
+            && self.founded_to.is_none()
+            && self.active_during.is_none()
+            && self.disbanded_after.is_none()
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        // Legacy dates which could not be read never match a bound on them
+        let creation = Some(entry.creation).filter(|&d| d != Date::UNKNOWN);
+        let disbandment = entry.disbandment;
+        let disbanded_known = disbandment != Some(Date::UNKNOWN);
+
+        if let Some(from) = self.founded_from {
+            if creation.map_or(true, |c| c < from) {
+                return false;
+            }
+        }
+
+        if let Some(to) = self.founded_to {
+            if creation.map_or(true, |c| c > to) {
+                return false;
+            }
+        }
+
+        if let Some((from, to)) = self.active_during {
+            if !disbanded_known || creation.map_or(true, |c| c > to) || disbandment.map_or(false, |d| d < from) {
+                return false;
+            }
+        }
+
+        if let Some(after) = self.disbanded_after {
+            if !disbanded_known || disbandment.map_or(true, |d| d < after) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Entry {
     id: i32, // Integer ID
@@ -40,12 +129,32 @@ pub struct Entry {
     desc_eng: String, // English description
     files: Vec<String>, // Files
     icon: Option<String>, // File used as icon
-    creation: String, // YYYY-MM-DD
-    disbandment: Option<String>, // YYYY-MM-DD
+
+    // Captions of attached files, by file name
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    captions: BTreeMap<String, String>,
+
+    // Legacy free-form values are tolerated on load, see date::lenient
+    #[serde(deserialize_with="date::lenient")]
+    creation: Date,
+    #[serde(default, deserialize_with="date::lenient_opt")]
+    disbandment: Option<Date>,
+
+    // Email address club keys are delivered to, never exposed through public queries
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    contact: Option<String>,
 
     #[serde(default = "get_false", skip_serializing_if="is_false")]
     deleted: bool,
 
+    // Unix timestamp, s, of the deletion
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    deleted_at: Option<u64>,
+
+    // Deleted, with the content and files removed for good. Kept so that ids stay dense
+    #[serde(default = "get_false", skip_serializing_if="is_false")]
+    purged: bool,
+
     #[serde(default = "get_false", skip_serializing_if="is_false")]
     hidden: bool,
 }
@@ -62,6 +171,181 @@ impl Entry {
     pub fn name_eng(&self) -> &str {
         &self.name_eng
     }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn caption(&self, file: &str) -> Option<&str> {
+        self.captions.get(file).map(String::as_str)
+    }
+
+    pub fn contact(&self) -> Option<&str> {
+        self.contact.as_ref().map(String::as_str)
+    }
+
+    // Neither deleted nor hidden, so that anyone may see it
+    pub fn is_public(&self) -> bool {
+        !self.deleted && !self.hidden
+    }
+
+    // Strips fields only administrators and the club itself may see
+    pub fn public(mut self) -> Entry {
+        self.contact = None;
+        self
+    }
+
+    fn validate(&self, categories: &HashMap<i32, Category>) -> Result<(), StoreError> {
+        if self.name.trim().is_empty() {
+            return Err(StoreError::EmptyName);
+        }
+
+        if !categories.values().any(|c| c.name == self.category) {
+            return Err(StoreError::UnknownCategory);
+        }
+
+        if let Some(d) = self.disbandment {
+            if d < self.creation {
+                return Err(StoreError::DisbandmentBeforeCreation);
+            }
+        }
+
+        if let Some(ref icon) = self.icon {
+            if !self.files.contains(icon) {
+                return Err(StoreError::IconNotInFiles);
+            }
+        }
+
+        if self.captions.keys().any(|f| !self.files.contains(f)) {
+            return Err(StoreError::CaptionNotInFiles);
+        }
+
+        if let Some(contact) = self.contact.as_ref().filter(|c| !c.is_empty()) {
+            let valid = contact.split('@').count() == 2
+                && !contact.starts_with('@')
+                && !contact.ends_with('@')
+                && !contact.chars().any(|c| c.is_whitespace() || c.is_control() || c == '<' || c == '>');
+            if !valid {
+                return Err(StoreError::InvalidContact);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Category {
+    #[serde(default)]
+    id: i32,
+    name: String, // Name, referenced by Entry.category
+    name_eng: String, // English name
+    order: i32, // Categories are listed in ascending order
+    color: String, // CSS color
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CategoryCount {
+    #[serde(flatten)]
+    category: Category,
+    count: usize, // Visible entries within this category
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TagCount {
+    tag: String,
+    count: usize, // Entries using this tag
+}
+
+// Latest delivery of a club key to the contact of an entry
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Delivery {
+    #[serde(default)]
+    pub id: String,
+    pub entry: i32,
+    pub key: u32, // Key id
+    pub to: String,
+    pub time: u64, // Unix timestamp, s
+    pub ok: bool,
+    #[serde(default)]
+    pub pending: bool, // Still being sent
+    pub error: Option<String>,
+}
+
+// Metadata of an uploaded file, recorded once the upload completes
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileRecord {
+    pub name: String, // <entry>.<uuid>.<ext>
+    pub entry: i32,
+    pub original: String, // Name of the file on the uploader's side
+    pub mime: String,
+    pub size: u64, // Bytes
+    pub sha256: String, // Hex
+    pub uploader: Option<String>, // As in the audit log
+    pub created: u64, // Unix timestamp, s
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub caption: Option<String>,
+}
+
+// A recorded file no entry refers to
+#[derive(Serialize, Clone, Debug)]
+pub struct Orphan {
+    pub name: String,
+    pub entry: i32,
+    pub size: u64, // Bytes
+    pub since: u64, // Unix timestamp, s, when first found unreferenced
+}
+
+// One-time link to a club key
+#[derive(Serialize, Deserialize)]
+struct Redeemable {
+    key: u32, // Key id
+    expiry: u64, // Unix timestamp, s
+}
+
+// One administrative action, appended to the audit log
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    #[serde(default)]
+    pub seq: u64, // Assigned when appended
+    pub time: u64, // Unix timestamp, s
+    pub actor: String, // "secret", "account:<name>" or "key:<id>"
+    pub cmd: String,
+    pub target: Option<i32>, // Entry
+    pub detail: Option<String>, // e.g. file name or account name
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(actor: String, cmd: String) -> AuditRecord {
+        AuditRecord {
+            seq: 0,
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            actor,
+            cmd,
+            target: None,
+            detail: None,
+            ok: false,
+            error: None,
+        }
+    }
+}
+
+// Replaces tags listed in `from` with `into`, returns false if nothing is changed
+fn retag(tags: &mut Vec<String>, from: &[String], into: &str) -> bool {
+    if !tags.iter().any(|t| from.contains(t)) {
+        return false;
+    }
+
+    for tag in tags.iter_mut() {
+        if from.contains(tag) {
+            *tag = into.to_owned();
+        }
+    }
+    tags.sort();
+    tags.dedup();
+    true
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -77,7 +361,7 @@ pub struct RawEntry {
 }
 
 impl RawEntry {
-    pub fn extend(self, id: i32) -> Entry {
+    pub fn extend(self, id: i32) -> Result<Entry, StoreError> {
         let trimmed = self.tags.trim();
         let tags = if trimmed == "" {
             vec![]
@@ -85,7 +369,12 @@ impl RawEntry {
             self.tags.split(" ").map(str::to_owned).collect()
         };
 
-        Entry {
+        let disbandment = match self.disbandment {
+            Some(ref d) if d.trim() != "" => Some(d.trim().parse()?),
+            _ => None,
+        };
+
+        Ok(Entry {
             id,
             name: self.name,
             name_eng: self.name_eng,
@@ -95,27 +384,58 @@ impl RawEntry {
             desc_eng: self.desc_eng,
             files: vec![],
             icon: None,
-            creation: self.creation,
-            disbandment: self.disbandment,
+            captions: BTreeMap::new(),
+            creation: self.creation.trim().parse()?,
+            disbandment,
+            contact: None,
             deleted: false,
+            deleted_at: None,
+            purged: false,
             hidden: false,
-        }
+        })
     }
 }
 
+// Rewrites the <n> lines referring to attached files in a description, after they are rearranged
+fn renumber_references(desc: &str, moved: &[usize]) -> String {
+    desc.split('\n').map(|line| {
+        let index = if line.starts_with('<') && line.ends_with('>') {
+            line[1..line.len()-1].parse::<usize>().ok()
+        } else {
+            None
+        };
+
+        match index.and_then(|i| i.checked_sub(1)).and_then(|i| moved.get(i)) {
+            Some(n) => format!("<{}>", n + 1),
+            None => line.to_owned(),
+        }
+    }).collect::<Vec<_>>().join("\n")
+}
+
+// Who submitted a stashed change
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Author {
+    pub actor: String, // As in the audit log, e.g. "key:3"
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub label: Option<String>, // Label of the key
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct StashedEntry {
     #[serde(flatten)]
     entry: Entry,
     timestamp: u64,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    author: Option<Author>,
 }
 
 impl StashedEntry {
-    fn create(entry: Entry) -> Self {
+    fn create(entry: Entry, author: Option<Author>) -> Self {
         let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         StashedEntry{
             entry: entry,
-            timestamp: t.as_secs() * 1000 + t.subsec_nanos() as u64 / 1_000_000
+            timestamp: t.as_secs() * 1000 + t.subsec_nanos() as u64 / 1_000_000,
+            author,
         }
     }
 
@@ -150,9 +470,39 @@ pub enum StoreError {
     Denied,
     InvalidString,
     DeletedEntry,
+    InvalidDate,
+    DisbandmentBeforeCreation,
+    EmptyName,
+    IconNotInFiles,
+    NotDeleted,
+    CaptionNotInFiles,
+    InvalidOrder,
+    UnknownCategory,
+    DuplicateCategory,
+    SameCategory,
+    EmptyTag,
+    UnknownTag,
+    InvalidAccountName,
+    LastSuperadmin,
+    InvalidContact,
+    NoContact,
+    InvalidFileName,
+    FileTooLarge,
+    QuotaExceeded,
+    FileTypeNotAllowed,
+    ImageTooLarge,
+    InvalidOffset,
+    IncompleteUpload,
+    ChecksumMismatch,
     SystemError,
 }
 
+impl From<DateError> for StoreError {
+    fn from(_: DateError) -> StoreError {
+        StoreError::InvalidDate
+    }
+}
+
 impl fmt::Display for StoreError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "StoreError")
@@ -166,12 +516,36 @@ impl Error for StoreError {
             StoreError::Denied => "Operation denied",
             StoreError::InvalidString => "Invalid String: contains NUL",
             StoreError::DeletedEntry => "Deleted entries cannot be modified",
+            StoreError::InvalidDate => "Invalid date: expecting YYYY-MM-DD",
+            StoreError::DisbandmentBeforeCreation => "Disbandment date is earlier than creation date",
+            StoreError::EmptyName => "Name cannot be empty",
+            StoreError::IconNotInFiles => "Icon is not one of the attached files",
+            StoreError::NotDeleted => "Entry is not deleted",
+            StoreError::CaptionNotInFiles => "Caption is not for one of the attached files",
+            StoreError::InvalidOrder => "New order does not contain exactly the attached files",
+            StoreError::UnknownCategory => "Unknown category",
+            StoreError::DuplicateCategory => "Category already exists",
+            StoreError::SameCategory => "Cannot merge a category into itself",
+            StoreError::EmptyTag => "Tag cannot be empty",
+            StoreError::UnknownTag => "Tag is not in the vocabulary",
+            StoreError::InvalidAccountName => "Account names cannot be empty or contain ':'",
+            StoreError::LastSuperadmin => "Cannot delete or demote the last superadmin",
+            StoreError::InvalidContact => "Contact is not a valid email address",
+            StoreError::NoContact => "Entry has no contact address",
+            StoreError::InvalidFileName => "Invalid file name",
+            StoreError::FileTooLarge => "File exceeds the size limit",
+            StoreError::QuotaExceeded => "Storage quota of the entry exceeded",
+            StoreError::FileTypeNotAllowed => "File type is not allowed, or does not match the extension",
+            StoreError::ImageTooLarge => "Image exceeds the pixel limit",
+            StoreError::InvalidOffset => "Chunk does not continue the upload",
+            StoreError::IncompleteUpload => "Upload is not complete yet",
+            StoreError::ChecksumMismatch => "SHA-256 of the upload does not match",
             StoreError::SystemError => "Cannot invoke system API",
         }
     }
 }
 
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Copy, Debug)]
 pub enum IndexType {
     Name,
     NameSeg,
@@ -202,9 +576,36 @@ impl Index {
     }
 }
 
+// On-disk form of a single posting: (id, type, count)
+type Posting = (i32, IndexType, i64);
+
+// Indexed fields of an entry, persisted alongside the index
+// so that stale entries can be detected without re-segmenting everything
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+struct IndexedFields {
+    name: String,
+    name_eng: String,
+    category: String,
+    tags: Vec<String>,
+}
+
+impl<'a> From<&'a Entry> for IndexedFields {
+    fn from(entry: &'a Entry) -> IndexedFields {
+        let mut tags = entry.tags.clone();
+        tags.sort();
+        IndexedFields {
+            name: entry.name.clone(),
+            name_eng: entry.name_eng.clone(),
+            category: entry.category.clone(),
+            tags,
+        }
+    }
+}
+
 struct InternalStore {
     entries: HashMap<i32, Entry>,
     index: HashMap<String, HashMap<Index, i64>>,
+    dirty: HashSet<String>, // Index keys modified since last sync
 }
 
 impl InternalStore {
@@ -238,12 +639,38 @@ impl InternalStore {
         Ok(())
     }
 
+    fn index_fields(&mut self, id: i32, fields: &IndexedFields) -> Result<(), StoreError> {
+        self.add_index(fields.name.clone(), Index::new(id, IndexType::Name));
+        self.add_index(fields.name_eng.clone(), Index::new(id, IndexType::Name));
+        self.add_name_seg(fields.name.clone(), id)?;
+        self.add_name_seg(fields.name_eng.clone(), id)?;
+        self.add_index(fields.category.clone(), Index::new(id, IndexType::Category));
+        for tag in &fields.tags {
+            self.add_index(tag.clone(), Index::new(id, IndexType::Tag));
+        }
+        Ok(())
+    }
+
+    fn unindex_fields(&mut self, id: i32, fields: &IndexedFields) -> Result<(), StoreError> {
+        self.del_index(fields.name.clone(), Index::new(id, IndexType::Name));
+        self.del_index(fields.name_eng.clone(), Index::new(id, IndexType::Name));
+        self.del_name_seg(fields.name.clone(), id)?;
+        self.del_name_seg(fields.name_eng.clone(), id)?;
+        self.del_index(fields.category.clone(), Index::new(id, IndexType::Category));
+        for tag in &fields.tags {
+            self.del_index(tag.clone(), Index::new(id, IndexType::Tag));
+        }
+        Ok(())
+    }
+
     fn add_index(&mut self, key: String, target: Index) {
+        self.dirty.insert(key.clone());
         let entry = self.index.entry(key).or_insert_with(HashMap::new);
         *(entry.entry(target).or_insert(0)) += 1;
     }
 
     fn del_index(&mut self, key: String, target: Index) -> bool {
+        self.dirty.insert(key.clone());
         let mut entry = match self.index.entry(key) {
             Vacant(_) => return false,
             Occupied(mut entry) => entry
@@ -263,27 +690,64 @@ impl InternalStore {
     }
 
     fn mem_del(&mut self, id: i32) -> Result<Vec<u8>, StoreError> {
-        let mut entry = match self.entries.entry(id) {
-            Vacant(_) => return Err(StoreError::NotFound),
-            Occupied(mut entry)  => entry,
+        let fields = match self.entries.get(&id) {
+            None => return Err(StoreError::NotFound),
+            Some(entry) => IndexedFields::from(entry),
         };
 
-        let entry = entry.get_mut();
-
-        self.del_index(entry.name.clone(), Index::new(entry.id, IndexType::Name));
-        self.del_index(entry.name_eng.clone(), Index::new(entry.id, IndexType::Name));
-        self.del_name_seg(entry.name.clone(), entry.id)?;
-        self.del_name_seg(entry.name_eng.clone(), entry.id)?;
-        self.del_index(entry.category.clone(), Index::new(entry.id, IndexType::Category));
-        for tag in &entry.tags {
-            self.del_index(tag.clone(), Index::new(entry.id, IndexType::Tag));
-        }
+        self.unindex_fields(id, &fields)?;
 
+        let entry = self.entries.get_mut(&id).unwrap();
         entry.deleted = true;
+        entry.deleted_at = Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
         let result = serde_json::to_vec(entry).unwrap();
         Ok(result)
     }
 
+    // Files in `missing` no longer exist, and are dropped from the entry, together with their icon and captions
+    fn mem_restore(&mut self, id: i32, missing: &HashSet<String>) -> Result<Vec<u8>, StoreError> {
+        let fields = match self.entries.get(&id) {
+            Some(entry) if entry.deleted && !entry.purged => IndexedFields::from(entry),
+            Some(entry) if !entry.deleted => return Err(StoreError::NotDeleted),
+            _ => return Err(StoreError::NotFound),
+        };
+
+        self.index_fields(id, &fields)?;
+
+        let entry = self.entries.get_mut(&id).unwrap();
+        entry.deleted = false;
+        entry.deleted_at = None;
+
+        entry.files.retain(|f| !missing.contains(f));
+        if entry.icon.as_ref().map_or(false, |i| !entry.files.contains(i)) {
+            entry.icon = None;
+        }
+        let captions = ::std::mem::replace(&mut entry.captions, BTreeMap::new());
+        entry.captions = captions.into_iter().filter(|&(ref f, _)| entry.files.contains(f)).collect();
+
+        Ok(serde_json::to_vec(entry).unwrap())
+    }
+
+    // Leaves a tombstone with nothing but the id and dates
+    fn mem_purge(&mut self, id: i32) -> Result<Vec<u8>, StoreError> {
+        let entry = self.entries.get_mut(&id).ok_or(StoreError::NotFound)?;
+        if !entry.deleted {
+            return Err(StoreError::NotDeleted);
+        }
+
+        entry.name = String::new();
+        entry.name_eng = String::new();
+        entry.tags = vec![];
+        entry.desc = String::new();
+        entry.desc_eng = String::new();
+        entry.files = vec![];
+        entry.icon = None;
+        entry.captions = BTreeMap::new();
+        entry.contact = None;
+        entry.purged = true;
+        Ok(serde_json::to_vec(entry).unwrap())
+    }
+
     fn mem_load(&mut self, entry: Entry) {
         self.entries.insert(entry.id, entry);
     }
@@ -296,14 +760,7 @@ impl InternalStore {
         if original.is_none() {
             let result = serde_json::to_vec(&entry).unwrap();
             let id = entry.id;
-            self.add_index(entry.name.clone(), Index::new(entry.id, IndexType::Name));
-            self.add_index(entry.name_eng.clone(), Index::new(entry.id, IndexType::Name));
-            self.add_name_seg(entry.name.clone(), entry.id)?;
-            self.add_name_seg(entry.name_eng.clone(), entry.id)?;
-            self.add_index(entry.category.clone(), Index::new(entry.id, IndexType::Category));
-            for tag in &entry.tags {
-                self.add_index(tag.clone(), Index::new(entry.id, IndexType::Tag));
-            }
+            self.index_fields(id, &IndexedFields::from(&entry))?;
             self.entries.insert(id, entry);
             return Ok((id, result))
         }
@@ -337,8 +794,10 @@ impl InternalStore {
 
         // Sorting tags
         entry.tags.sort();
+        let mut original_tags = original.tags.clone();
+        original_tags.sort();
         let mut ctags = entry.tags.iter();
-        let mut otags = original.tags.iter();
+        let mut otags = original_tags.iter();
 
         let mut ctag = ctags.next();
         let mut otag = otags.next();
@@ -386,7 +845,8 @@ impl InternalStore {
     fn filter<'a, T: Iterator<Item=&'a str>>(
         &self,
         avail: Option<Availability>,
-        keywords: Option<T>) -> Vec<Entry> {
+        keywords: Option<T>,
+        lifecycle: Option<&Lifecycle>) -> Vec<Entry> {
         // TODO: Impl
         let mut hash: HashMap<i32,i64> = HashMap::new();
         let words = if let Some(iter) = keywords {
@@ -396,9 +856,10 @@ impl InternalStore {
         } else {
             let source = self.entries
                 .values()
-                .filter(|e| !e.deleted && !e.hidden);
+                .filter(|e| !e.deleted && !e.hidden)
+                .filter(|e| lifecycle.map_or(true, |l| l.matches(e)));
             let mut result: Vec<Entry> = match avail {
-                None => source.cloned().collect(),
+                None | Some(Availability::All) => source.cloned().collect(),
                 Some(Availability::Available) =>
                     source.filter(|e| e.disbandment.is_none()).cloned().collect(),
                 Some(Availability::Disbanded) =>
@@ -424,9 +885,12 @@ impl InternalStore {
 
         ids.sort_unstable_by_key(|i| { (-hash[i], &self.entries[i].name) });
 
-        let it = ids.iter().map(|i| &self.entries[i]);
+        let it = ids.iter()
+            .map(|i| &self.entries[i])
+            .filter(|e| lifecycle.map_or(true, |l| l.matches(e)));
         if let Some(a) = avail {
             match a {
+                Availability::All => it.cloned().collect(),
                 Availability::Available => it.filter(|e| e.disbandment.is_none()).cloned().collect(),
                 Availability::Disbanded => it.filter(|e| e.disbandment.is_some()).cloned().collect(),
             }
@@ -447,10 +911,56 @@ impl InternalStore {
     fn highest_id(&self) -> i32 {
         self.entries.keys().max().cloned().unwrap_or(0)
     }
+
+    fn tags(&self) -> Vec<TagCount> {
+        let mut result: Vec<TagCount> = self.index.iter().filter_map(|(k, bucket)| {
+            let count = bucket.keys().filter(|i| i.t == IndexType::Tag).count();
+            if count == 0 {
+                None
+            } else {
+                Some(TagCount { tag: k.clone(), count })
+            }
+        }).collect();
+        result.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+        result
+    }
+
+    fn indexed(&self, id: i32) -> Option<IndexedFields> {
+        match self.entries.get(&id) {
+            Some(e) if !e.deleted => Some(IndexedFields::from(e)),
+            _ => None,
+        }
+    }
+
+    // Lists entries whose indexed fields differ from the persisted snapshots
+    fn stale(&self, snapshots: &HashMap<i32, IndexedFields>) -> Vec<i32> {
+        let ids: HashSet<i32> = self.entries.keys().chain(snapshots.keys()).cloned().collect();
+        let mut result: Vec<i32> = ids.into_iter()
+            .filter(|id| self.indexed(*id).as_ref() != snapshots.get(id))
+            .collect();
+        result.sort_unstable();
+        result
+    }
+
+    // Builds the index from scratch, leaving the current one untouched
+    fn fresh_index(&self) -> Result<HashMap<String, HashMap<Index, i64>>, StoreError> {
+        let mut fresh = InternalStore {
+            entries: HashMap::new(),
+            index: HashMap::new(),
+            dirty: HashSet::new(),
+        };
+
+        for entry in self.entries.values().filter(|e| !e.deleted) {
+            fresh.index_fields(entry.id, &IndexedFields::from(entry))?;
+        }
+        Ok(fresh.index)
+    }
 }
 
 pub struct Store {
     db: Database<i32>,
+    meta: Meta,
+    categories: HashMap<i32, Category>,
     stash: HashMap<i32, StashedEntry>,
     internal: InternalStore,
 }
@@ -460,6 +970,7 @@ impl Store {
         let mut dbopt = Options::new();
         dbopt.create_if_missing = true;
         let db = Database::open(Path::new("./db"), dbopt).unwrap();
+        let meta = Meta::open(Path::new("./meta"));
 
         let stash = File::open(Path::new("./stash.json"))
             .map(|f| serde_json::from_reader(f).unwrap())
@@ -467,25 +978,140 @@ impl Store {
 
         let mut store = Store {
             db,
+            meta,
+            categories: HashMap::new(),
             stash,
             internal: InternalStore {
                 entries: HashMap::new(),
                 index: HashMap::new(),
+                dirty: HashSet::new(),
             },
         };
 
         let iter = store.db.iter(ReadOptions::new());
         for (_, slice) in iter {
             let entry: Entry = serde_json::from_slice(&slice).unwrap();
-            if entry.deleted {
-                store.internal.mem_load(entry);
-            } else {
-                store.internal.mem_put(entry).unwrap();
-            }
+            store.internal.mem_load(entry);
         }
+        store.load_index().unwrap();
+        store.load_categories();
         store
     }
 
+    fn load_categories(&mut self) {
+        self.categories = self.meta
+            .scan::<Category>(CATEGORY_SPACE)
+            .into_iter()
+            .map(|(_, c)| (c.id, c))
+            .collect();
+
+        // Categories given to entries before the registry existed, so that those can still be edited and merged away.
+        // Purged entries have no content left to categorize
+        let known: HashSet<String> = self.categories.values().map(|c| c.name.clone()).collect();
+        let mut unknown: Vec<String> = self.internal.entries
+            .values()
+            .filter(|e| !e.purged)
+            .chain(self.stash.values().map(StashedEntry::content))
+            .map(|e| e.category.clone())
+            .filter(|c| !c.trim().is_empty() && !known.contains(c))
+            .collect();
+        unknown.sort();
+        unknown.dedup();
+
+        for name in unknown {
+            let category = Category {
+                id: self.categories.keys().max().cloned().unwrap_or(0) + 1,
+                order: self.categories.values().map(|c| c.order).max().unwrap_or(-1) + 1,
+                name_eng: name.clone(),
+                name,
+                color: UNSORTED_COLOR.to_owned(),
+            };
+            self.meta.put(CATEGORY_SPACE, &category.id.to_string(), &category);
+            self.categories.insert(category.id, category);
+        }
+    }
+
+    // Loads the persisted index, then re-indexes entries changed since the last sync
+    fn load_index(&mut self) -> Result<(), StoreError> {
+        for (key, postings) in self.meta.scan::<Vec<Posting>>(INDEX_SPACE) {
+            let bucket = postings
+                .into_iter()
+                .map(|(id, t, count)| (Index::new(id, t), count))
+                .collect();
+            self.internal.index.insert(key, bucket);
+        }
+
+        let snapshots: HashMap<i32, IndexedFields> = self.meta
+            .scan(INDEXED_SPACE)
+            .into_iter()
+            .filter_map(|(k, v)| k.parse().ok().map(|id| (id, v)))
+            .collect();
+
+        for id in self.internal.stale(&snapshots) {
+            if let Some(fields) = snapshots.get(&id) {
+                self.internal.unindex_fields(id, fields)?;
+            }
+            if let Some(fields) = self.internal.indexed(id) {
+                self.internal.index_fields(id, &fields)?;
+            }
+            self.sync_index(&[id]);
+        }
+        Ok(())
+    }
+
+    // Persists modified index keys together with the snapshots of given entries
+    fn sync_index(&mut self, ids: &[i32]) {
+        let mut batch = MetaBatch::new();
+        for key in self.internal.dirty.drain() {
+            match self.internal.index.get(&key) {
+                Some(bucket) if !bucket.is_empty() => {
+                    let postings: Vec<Posting> = bucket
+                        .iter()
+                        .map(|(index, count)| (index.id, index.t, *count))
+                        .collect();
+                    batch.put(INDEX_SPACE, &key, &postings);
+                },
+                _ => batch.delete(INDEX_SPACE, &key),
+            }
+        }
+
+        for id in ids {
+            match self.internal.indexed(*id) {
+                Some(fields) => batch.put(INDEXED_SPACE, &id.to_string(), &fields),
+                None => batch.delete(INDEXED_SPACE, &id.to_string()),
+            }
+        }
+        self.meta.write(batch);
+    }
+
+    // Lists index keys that differ from an index built from scratch
+    pub fn verify_index(&self) -> Result<Vec<String>, StoreError> {
+        let fresh = self.internal.fresh_index()?;
+        let nonempty = |b: Option<&HashMap<Index, i64>>| {
+            b.and_then(|b| if b.is_empty() { None } else { Some(b) })
+        };
+
+        let keys: HashSet<&String> = fresh.keys().chain(self.internal.index.keys()).collect();
+        let mut result: Vec<String> = keys.into_iter()
+            .filter(|k| nonempty(fresh.get(*k)) != nonempty(self.internal.index.get(*k)))
+            .cloned()
+            .collect();
+        result.sort();
+        Ok(result)
+    }
+
+    pub fn rebuild_index(&mut self) -> Result<(), StoreError> {
+        let fresh = self.internal.fresh_index()?;
+        self.meta.clear(INDEX_SPACE);
+        self.meta.clear(INDEXED_SPACE);
+
+        self.internal.index = fresh;
+        self.internal.dirty = self.internal.index.keys().cloned().collect();
+        let ids: Vec<i32> = self.internal.entries.keys().cloned().collect();
+        self.sync_index(&ids);
+        Ok(())
+    }
+
     pub fn close(&mut self) {
         println!("Syncing storage...");
         let stash = File::create(Path::new("./stash.json")).unwrap();
@@ -517,7 +1143,9 @@ impl Store {
             .or_else(|| { self.fetch(id).map(PullEntry::Unmodified) })
     }
 
-    pub fn stash(&mut self, mut entry: Entry, restricted: bool) -> Result<(), StoreError> {
+    pub fn stash(&mut self, mut entry: Entry, restricted: bool, author: Option<Author>) -> Result<(), StoreError> {
+        entry.validate(&self.categories)?;
+
         if entry.id > self.internal.len() {
             // Is a new entry
 
@@ -526,7 +1154,7 @@ impl Store {
             }
 
             let id = entry.id;
-            self.stash.insert(id, StashedEntry::create(entry.clone()));
+            self.stash.insert(id, StashedEntry::create(entry.clone(), author));
 
             entry.hidden = true;
             self.put(entry)
@@ -535,11 +1163,81 @@ impl Store {
             Ok(())
         } else {
             let id = entry.id;
-            self.stash.insert(id, StashedEntry::create(entry));
+            self.stash.insert(id, StashedEntry::create(entry, author));
             Ok(())
         }
     }
 
+    // Stashed version of the entry if there is one, otherwise the committed one
+    pub fn latest(&self, id: i32) -> Option<Entry> {
+        match self.stash.get(&id) {
+            Some(stashed) => Some(stashed.content().clone()),
+            None => self.fetch(id),
+        }
+    }
+
+    // Adds an uploaded file to the latest version of the entry, optionally as its icon
+    pub fn attach_file(
+        &mut self,
+        id: i32,
+        name: &str,
+        icon: bool,
+        restricted: bool,
+        author: Option<Author>) -> Result<(), StoreError> {
+
+        let mut entry = self.latest(id).ok_or(StoreError::NotFound)?;
+        if entry.deleted {
+            return Err(StoreError::DeletedEntry);
+        }
+
+        if !entry.files.iter().any(|f| f == name) {
+            entry.files.push(name.to_owned());
+        }
+
+        if icon {
+            entry.icon = Some(name.to_owned());
+        }
+
+        self.stash(entry, restricted, author)
+    }
+
+    // Rearranges the attached files of the latest version of the entry, optionally replacing their captions
+    pub fn reorder_files(
+        &mut self,
+        id: i32,
+        files: Vec<String>,
+        captions: Option<BTreeMap<String, String>>,
+        restricted: bool,
+        author: Option<Author>) -> Result<(), StoreError> {
+
+        let mut entry = self.latest(id).ok_or(StoreError::NotFound)?;
+        if entry.deleted {
+            return Err(StoreError::DeletedEntry);
+        }
+
+        let mut current = entry.files.clone();
+        let mut requested = files.clone();
+        current.sort_unstable();
+        requested.sort_unstable();
+        if current != requested {
+            return Err(StoreError::InvalidOrder);
+        }
+
+        // Descriptions refer to attached files by position
+        let moved: Vec<usize> = entry.files.iter()
+            .map(|f| files.iter().position(|n| n == f).unwrap())
+            .collect();
+        entry.desc = renumber_references(&entry.desc, &moved);
+        entry.desc_eng = renumber_references(&entry.desc_eng, &moved);
+
+        entry.files = files;
+        if let Some(captions) = captions {
+            entry.captions = captions.into_iter().filter(|&(_, ref c)| !c.trim().is_empty()).collect();
+        }
+
+        self.stash(entry, restricted, author)
+    }
+
     pub fn commit(&mut self, id: i32) -> Result<(), StoreError> {
         match self.stash.entry(id) {
             Vacant(_) => Ok(()),
@@ -559,21 +1257,72 @@ impl Store {
     fn put(&mut self, entry: Entry) -> Result<(), StoreError> {
         let (id, content) = self.internal.mem_put(entry)?;
         self.db.put(WriteOptions::new(), id, &content).unwrap();
+        self.sync_index(&[id]);
         Ok(())
     }
 
     pub fn del(&mut self, id: i32) -> Result<(), StoreError> {
         let entry = self.internal.mem_del(id)?;
         self.db.put(WriteOptions::new(), id, &entry).unwrap();
+        self.sync_index(&[id]);
         self.stash.remove(&id);
         Ok(())
     }
 
+    // Deleted entries which are not purged, most recently deleted first
+    pub fn deleted(&self) -> Vec<Entry> {
+        let mut result: Vec<Entry> = self.internal.entries
+            .values()
+            .filter(|e| e.deleted && !e.purged)
+            .cloned()
+            .collect();
+        result.sort_unstable_by_key(|e| (::std::cmp::Reverse(e.deleted_at), e.id));
+        result
+    }
+
+    // Undoes a deletion. Files collected meanwhile are no longer attached
+    pub fn restore(&mut self, id: i32) -> Result<(), StoreError> {
+        // Files removed meanwhile are dropped within the same write
+        let missing: HashSet<String> = self.internal.entries
+            .get(&id)
+            .map(|e| e.files.iter().filter(|f| self.file(f).is_none()).cloned().collect())
+            .unwrap_or_default();
+
+        let content = self.internal.mem_restore(id, &missing)?;
+        self.db.put(WriteOptions::new(), id, &content).unwrap();
+        self.sync_index(&[id]);
+        Ok(())
+    }
+
+    // Removes a deleted entry for good, together with every file uploaded for it.
+    // Returns the digests of blobs no other file refers to
+    pub fn purge(&mut self, id: i32) -> Result<Vec<String>, StoreError> {
+        let content = self.internal.mem_purge(id)?;
+        self.db.put(WriteOptions::new(), id, &content).unwrap();
+        self.meta.delete(DELIVERY_SPACE, &id.to_string());
+
+        // Nothing is left to log in to, so every key of the entry goes, legacy ones included
+        let mut revoked = vec![0];
+        revoked.extend(self.keys(Some(id)).into_iter().map(|k| k.id));
+        for key in revoked {
+            self.meta.put(REVOKED_SPACE, &Store::revocation_key(key, id), &true);
+        }
+
+        let mut unused = Vec::new();
+        for record in self.files(Some(id)) {
+            if let Some(sha256) = self.forget_file(&record.name)? {
+                unused.push(sha256);
+            }
+        }
+        Ok(unused)
+    }
+
     pub fn filter<'a, T: Iterator<Item=&'a str>>(
         &self,
         avail: Option<Availability>,
-        keywords: Option<T>) -> Vec<Entry> {
-        self.internal.filter(avail, keywords)
+        keywords: Option<T>,
+        lifecycle: Option<&Lifecycle>) -> Vec<Entry> {
+        self.internal.filter(avail, keywords, lifecycle)
     }
 
     pub fn fetch(&self, id: i32) -> Option<Entry> {
@@ -583,4 +1332,585 @@ impl Store {
     pub fn highest_id(&self) -> i32 {
         self.internal.highest_id()
     }
+
+    pub fn categories(&self) -> Vec<CategoryCount> {
+        let mut result: Vec<CategoryCount> = self.categories.values().map(|c| {
+            let count = self.internal.entries
+                .values()
+                .filter(|e| !e.deleted && !e.hidden && e.category == c.name)
+                .count();
+            CategoryCount { category: c.clone(), count }
+        }).collect();
+        result.sort_unstable_by_key(|c| (c.category.order, c.category.id));
+        result
+    }
+
+    fn check_category(&self, category: &Category) -> Result<(), StoreError> {
+        if category.name.trim().is_empty() {
+            return Err(StoreError::EmptyName);
+        }
+
+        if self.categories.values().any(|c| c.id != category.id && c.name == category.name) {
+            return Err(StoreError::DuplicateCategory);
+        }
+        Ok(())
+    }
+
+    pub fn create_category(&mut self, mut category: Category) -> Result<(), StoreError> {
+        category.id = self.categories.keys().max().cloned().unwrap_or(0) + 1;
+        self.check_category(&category)?;
+        self.meta.put(CATEGORY_SPACE, &category.id.to_string(), &category);
+        self.categories.insert(category.id, category);
+        Ok(())
+    }
+
+    // Updates a category, renaming it in all entries if needed. Returns the affected entries
+    pub fn update_category(&mut self, category: Category) -> Result<Vec<i32>, StoreError> {
+        let original = match self.categories.get(&category.id) {
+            None => return Err(StoreError::NotFound),
+            Some(c) => c.name.clone(),
+        };
+        self.check_category(&category)?;
+
+        // Entries are moved first, so that the registry never lacks a category still in use
+        let affected = self.recategorize(&original, &category.name)?;
+        self.meta.put(CATEGORY_SPACE, &category.id.to_string(), &category);
+        self.categories.insert(category.id, category);
+        Ok(affected)
+    }
+
+    // Moves all entries of one category into another. Returns the affected entries
+    pub fn merge_category(&mut self, from: i32, into: i32) -> Result<Vec<i32>, StoreError> {
+        if from == into {
+            return Err(StoreError::SameCategory);
+        }
+
+        let target = match self.categories.get(&into) {
+            None => return Err(StoreError::NotFound),
+            Some(c) => c.name.clone(),
+        };
+
+        let source = match self.categories.get(&from) {
+            None => return Err(StoreError::NotFound),
+            Some(c) => c.name.clone(),
+        };
+
+        // Removed only once no entry is left in it
+        let affected = self.recategorize(&source, &target)?;
+        self.meta.delete(CATEGORY_SPACE, &from.to_string());
+        self.categories.remove(&from);
+        Ok(affected)
+    }
+
+    pub fn tags(&self) -> Vec<TagCount> {
+        self.internal.tags()
+    }
+
+    // Tags clubs may use when the vocabulary is controlled, sorted
+    pub fn vocabulary(&self) -> Vec<String> {
+        self.meta
+            .scan::<bool>(TAG_SPACE)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect()
+    }
+
+    pub fn add_vocabulary(&mut self, tags: &[String]) -> Result<(), StoreError> {
+        let mut batch = MetaBatch::new();
+        for tag in tags {
+            if tag.trim().is_empty() {
+                return Err(StoreError::EmptyTag);
+            }
+            batch.put(TAG_SPACE, tag.trim(), &true);
+        }
+        self.meta.write(batch);
+        Ok(())
+    }
+
+    // Tags still in use stay on their entries, but clubs can no longer add them
+    pub fn remove_vocabulary(&mut self, tags: &[String]) {
+        let mut batch = MetaBatch::new();
+        for tag in tags {
+            batch.delete(TAG_SPACE, tag);
+        }
+        self.meta.write(batch);
+    }
+
+    // Controlled vocabulary: only tags in the vocabulary, or already on this entry, are allowed
+    pub fn check_vocabulary(&self, entry: &Entry) -> Result<(), StoreError> {
+        let current = self.internal.entries.get(&entry.id);
+        for tag in &entry.tags {
+            let kept = current.map_or(false, |c| c.tags.contains(tag));
+            if !kept && self.meta.get::<bool>(TAG_SPACE, tag).is_none() {
+                return Err(StoreError::UnknownTag);
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces tags in all entries within a single write, and in the vocabulary. Returns the affected entries
+    pub fn merge_tags(&mut self, from: &[String], into: &str) -> Result<Vec<i32>, StoreError> {
+        let into = into.trim();
+        if into.is_empty() {
+            return Err(StoreError::EmptyTag);
+        }
+
+        // Built in full before anything is written
+        let retagged: Vec<Entry> = self.internal.entries
+            .values()
+            .filter(|e| !e.deleted)
+            .filter_map(|e| {
+                let mut entry = e.clone();
+                if retag(&mut entry.tags, from, into) { Some(entry) } else { None }
+            })
+            .collect();
+        let mut affected = self.put_all(retagged)?;
+
+        if from.iter().any(|t| self.meta.get::<bool>(TAG_SPACE, t).is_some()) {
+            let mut batch = MetaBatch::new();
+            for tag in from {
+                batch.delete(TAG_SPACE, tag);
+            }
+            batch.put(TAG_SPACE, into, &true);
+            self.meta.write(batch);
+        }
+
+        for stashed in self.stash.values_mut() {
+            if retag(&mut stashed.entry.tags, from, into) {
+                affected.push(stashed.entry.id);
+            }
+        }
+
+        affected.sort_unstable();
+        affected.dedup();
+        Ok(affected)
+    }
+
+    pub fn account(&self, name: &str) -> Option<Account> {
+        self.meta.get(ACCOUNT_SPACE, name)
+    }
+
+    pub fn accounts(&self) -> Vec<Account> {
+        self.meta
+            .scan(ACCOUNT_SPACE)
+            .into_iter()
+            .map(|(_, a)| a)
+            .collect()
+    }
+
+    pub fn put_account(&mut self, account: &Account) -> Result<(), StoreError> {
+        if account.name().is_empty() || account.name().contains(':') {
+            return Err(StoreError::InvalidAccountName);
+        }
+        if account.role() != Role::Superadmin && self.is_last_superadmin(account.name()) {
+            return Err(StoreError::LastSuperadmin);
+        }
+        self.meta.put(ACCOUNT_SPACE, account.name(), account);
+        Ok(())
+    }
+
+    pub fn del_account(&mut self, name: &str) -> Result<(), StoreError> {
+        if self.account(name).is_none() {
+            return Err(StoreError::NotFound);
+        }
+        if self.is_last_superadmin(name) {
+            return Err(StoreError::LastSuperadmin);
+        }
+        self.meta.delete(ACCOUNT_SPACE, name);
+        Ok(())
+    }
+
+    // Whether the account is the only one left that can manage accounts
+    fn is_last_superadmin(&self, name: &str) -> bool {
+        let superadmins: Vec<Account> = self.accounts()
+            .into_iter()
+            .filter(|a| a.role() == Role::Superadmin)
+            .collect();
+        superadmins.len() == 1 && superadmins[0].name() == name
+    }
+
+    // Records a new club key, which is then sealed by KeyRing::generate_key with the secret `ring`
+    pub fn issue_key(
+        &mut self,
+        entry: i32,
+        label: Option<String>,
+        expiry: Option<u64>,
+        scope: Scope,
+        ring: u32) -> Result<Claims, StoreError> {
+        if self.fetch(entry).map_or(true, |e| e.deleted) {
+            return Err(StoreError::NotFound);
+        }
+
+        let id = self.keys(None).iter().map(|k| k.id).max().unwrap_or(0) + 1;
+        let issued = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let label = label.map(|l| l.trim().to_owned()).filter(|l| !l.is_empty());
+        let claims = Claims { id, ring, entry, label, issued, expiry, scope };
+        self.meta.put(KEY_SPACE, &id.to_string(), &claims);
+        Ok(claims)
+    }
+
+    // Record of an issued key, carrying its label
+    pub fn key(&self, id: u32) -> Option<Claims> {
+        self.meta.get(KEY_SPACE, &id.to_string())
+    }
+
+    // Issued keys, optionally of a single entry
+    pub fn keys(&self, entry: Option<i32>) -> Vec<Claims> {
+        let mut result: Vec<Claims> = self.meta
+            .scan::<Claims>(KEY_SPACE)
+            .into_iter()
+            .map(|(_, k)| k)
+            .filter(|k| entry.map_or(true, |e| k.entry == e))
+            .collect();
+        result.sort_unstable_by_key(|k| k.id);
+        result
+    }
+
+    // Legacy keys carry no id, so they can only be revoked all together for an entry
+    fn revocation_key(id: u32, entry: i32) -> String {
+        if id == 0 {
+            format!("legacy.{}", entry)
+        } else {
+            id.to_string()
+        }
+    }
+
+    pub fn revoke_key(&mut self, id: u32, entry: i32) -> Result<(), StoreError> {
+        if id != 0 && self.meta.get::<Claims>(KEY_SPACE, &id.to_string()).is_none() {
+            return Err(StoreError::NotFound);
+        }
+        self.meta.put(REVOKED_SPACE, &Store::revocation_key(id, entry), &true);
+        Ok(())
+    }
+
+    // Whether the keys issued to the entry before keys were recorded are revoked
+    pub fn is_legacy_revoked(&self, entry: i32) -> bool {
+        self.meta.get::<bool>(REVOKED_SPACE, &Store::revocation_key(0, entry)).is_some()
+    }
+
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        self.meta
+            .get::<bool>(REVOKED_SPACE, &Store::revocation_key(claims.id, claims.entry))
+            .is_some()
+    }
+
+    // Whether keys of the entry may authenticate. Entries not yet committed only exist in the stash,
+    // and purged entries are deleted as well
+    pub fn admits_keys(&self, entry: i32) -> bool {
+        match self.internal.entries.get(&entry) {
+            Some(e) => !e.deleted,
+            None => self.stash.contains_key(&entry),
+        }
+    }
+
+    // Creates a token which can be exchanged once for the key, within `validity` seconds
+    pub fn create_redeem(&mut self, claims: &Claims, validity: u64) -> Result<String, StoreError> {
+        let mut token = [0u8; 16];
+        SystemRandom::new().fill(&mut token).map_err(|_| StoreError::SystemError)?;
+        let token = to_hex(&token);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.meta.put(REDEEM_SPACE, &token, &Redeemable {
+            key: claims.id,
+            expiry: now + validity,
+        });
+        Ok(token)
+    }
+
+    // Consumes a token, returning the key it refers to if still valid
+    pub fn redeem(&mut self, token: &str) -> Option<Claims> {
+        let redeemable = self.meta.get::<Redeemable>(REDEEM_SPACE, token)?;
+        self.meta.delete(REDEEM_SPACE, token);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if redeemable.expiry <= now {
+            return None;
+        }
+
+        let claims = self.meta.get::<Claims>(KEY_SPACE, &redeemable.key.to_string())?;
+        if claims.is_expired() || self.is_revoked(&claims) {
+            return None;
+        }
+        Some(claims)
+    }
+
+    // Records the file, adding a reference to its blob
+    pub fn record_file(&mut self, record: &FileRecord) {
+        let mut batch = MetaBatch::new();
+        if self.file(&record.name).is_none() {
+            let refs = self.meta.get::<u64>(BLOB_SPACE, &record.sha256).unwrap_or(0);
+            batch.put(BLOB_SPACE, &record.sha256, &(refs + 1));
+        }
+        batch.put(FILE_SPACE, &record.name, record);
+        self.meta.write(batch);
+    }
+
+    pub fn file(&self, name: &str) -> Option<FileRecord> {
+        self.meta.get(FILE_SPACE, name)
+    }
+
+    // Records of uploaded files, optionally of a single entry, most recent first
+    pub fn files(&self, entry: Option<i32>) -> Vec<FileRecord> {
+        let mut result: Vec<FileRecord> = self.meta
+            .scan::<FileRecord>(FILE_SPACE)
+            .into_iter()
+            .map(|(_, f)| f)
+            .filter(|f| entry.map_or(true, |id| f.entry == id))
+            .collect();
+        result.sort_unstable_by(|a, b| (b.created, &b.name).cmp(&(a.created, &a.name)));
+        result
+    }
+
+    // Removes the record of a file. Returns the digest of its blob if no other file refers to it
+    pub fn forget_file(&mut self, name: &str) -> Result<Option<String>, StoreError> {
+        let record = self.file(name).ok_or(StoreError::NotFound)?;
+        let refs = self.meta.get::<u64>(BLOB_SPACE, &record.sha256).unwrap_or(1);
+
+        let mut batch = MetaBatch::new();
+        batch.delete(FILE_SPACE, name);
+        batch.delete(ORPHAN_SPACE, name);
+        let unused = if refs <= 1 {
+            batch.delete(BLOB_SPACE, &record.sha256);
+            Some(record.sha256)
+        } else {
+            batch.put(BLOB_SPACE, &record.sha256, &(refs - 1));
+            None
+        };
+        self.meta.write(batch);
+        Ok(unused)
+    }
+
+    pub fn is_blob_used(&self, sha256: &str) -> bool {
+        self.meta.get::<u64>(BLOB_SPACE, sha256).is_some()
+    }
+
+    // Files referred to by committed entries or by stashed changes. Deleted entries keep their files,
+    // so that they can be restored, until purged
+    fn referenced_files(&self) -> HashSet<String> {
+        let committed = self.internal.entries.values();
+        let stashed = self.stash.values().map(StashedEntry::content);
+        committed.chain(stashed)
+            .flat_map(|e| e.files.iter().chain(e.icon.iter()))
+            .cloned()
+            .collect()
+    }
+
+    // Lists files no entry refers to. Marks when each was first found so, and clears the marks of files referred to again
+    pub fn orphans(&mut self) -> Vec<Orphan> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let referenced = self.referenced_files();
+        let marks: HashMap<String, u64> = self.meta.scan(ORPHAN_SPACE).into_iter().collect();
+
+        let mut batch = MetaBatch::new();
+        for name in marks.keys().filter(|n| referenced.contains(*n)) {
+            batch.delete(ORPHAN_SPACE, name);
+        }
+
+        let mut result = Vec::new();
+        for record in self.files(None) {
+            if referenced.contains(&record.name) {
+                continue;
+            }
+
+            let since = match marks.get(&record.name) {
+                Some(since) => *since,
+                None => {
+                    batch.put(ORPHAN_SPACE, &record.name, &now);
+                    now
+                },
+            };
+
+            result.push(Orphan {
+                name: record.name,
+                entry: record.entry,
+                size: record.size,
+                since,
+            });
+        }
+
+        self.meta.write(batch);
+        result
+    }
+
+    // Rebuilds the reference counts of blobs from file records
+    pub fn recount_blobs(&mut self) {
+        let mut refs: HashMap<String, u64> = HashMap::new();
+        for record in self.files(None) {
+            *refs.entry(record.sha256).or_insert(0) += 1;
+        }
+
+        self.meta.clear(BLOB_SPACE);
+        let mut batch = MetaBatch::new();
+        for (sha256, count) in refs {
+            batch.put(BLOB_SPACE, &sha256, &count);
+        }
+        self.meta.write(batch);
+    }
+
+    pub fn record_delivery(&mut self, delivery: &Delivery) {
+        self.meta.put(DELIVERY_SPACE, &delivery.entry.to_string(), delivery);
+    }
+
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        let mut result: Vec<Delivery> = self.meta
+            .scan::<Delivery>(DELIVERY_SPACE)
+            .into_iter()
+            .map(|(_, d)| d)
+            .collect();
+        result.sort_unstable_by_key(|d| d.entry);
+        result
+    }
+
+    // Records are never modified or removed once appended
+    pub fn append_audit(&mut self, mut record: AuditRecord) -> u64 {
+        let seq = self.meta.get::<u64>(COUNTER_SPACE, AUDIT_SPACE).unwrap_or(0) + 1;
+        record.seq = seq;
+
+        // Zero-padded, so that keys are ordered by sequence
+        let mut batch = MetaBatch::new();
+        batch.put(AUDIT_SPACE, &format!("{:020}", seq), &record);
+        batch.put(COUNTER_SPACE, AUDIT_SPACE, &seq);
+        self.meta.write(batch);
+        seq
+    }
+
+    // At most `limit` records, starting from sequence `from`
+    pub fn audit(&self, from: u64, limit: usize) -> Vec<AuditRecord> {
+        self.meta
+            .scan_from::<AuditRecord>(AUDIT_SPACE, &format!("{:020}", from), limit)
+            .into_iter()
+            .map(|(_, r)| r)
+            .collect()
+    }
+
+    // Removes a deleted file from both committed and stashed entries, including their icons
+    pub fn detach_file(&mut self, name: &str) -> Result<Vec<i32>, StoreError> {
+        fn detach(entry: &mut Entry, name: &str) -> bool {
+            let before = entry.files.len();
+            entry.files.retain(|f| f != name);
+            let had_icon = entry.icon.as_ref().map_or(false, |i| i == name);
+            if had_icon {
+                entry.icon = None;
+            }
+            let had_caption = entry.captions.remove(name).is_some();
+            had_icon || had_caption || entry.files.len() != before
+        }
+
+        let mut affected = Vec::new();
+        let committed: Vec<Entry> = self.internal.entries
+            .values()
+            .filter(|e| !e.deleted)
+            .cloned()
+            .collect();
+
+        for mut entry in committed {
+            if detach(&mut entry, name) {
+                affected.push(entry.id);
+                self.put(entry)?;
+            }
+        }
+
+        for stashed in self.stash.values_mut() {
+            if detach(&mut stashed.entry, name) {
+                affected.push(stashed.entry.id);
+            }
+        }
+
+        affected.sort_unstable();
+        affected.dedup();
+        Ok(affected)
+    }
+
+    // Writes changes to several committed entries at once, then applies them to memory and the index.
+    // Callers only pass entries with tags sorted and names unchanged, so that applying them cannot fail after the write.
+    // Returns the ids of entries which are not deleted
+    fn put_all(&mut self, entries: Vec<Entry>) -> Result<Vec<i32>, StoreError> {
+        let mut batch = Writebatch::new();
+        for entry in &entries {
+            batch.put(entry.id, &serde_json::to_vec(entry).unwrap());
+        }
+        self.db.write(WriteOptions::new(), &batch).unwrap();
+
+        let mut ids = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if entry.deleted {
+                // Out of the index already, only kept for restoring
+                self.internal.mem_load(entry);
+            } else {
+                ids.push(entry.id);
+                self.internal.mem_put(entry)?;
+            }
+        }
+        self.sync_index(&ids);
+        Ok(ids)
+    }
+
+    // Rewrites the category of both committed and stashed entries.
+    // Deleted entries move as well, so that they are restored into a category that exists
+    fn recategorize(&mut self, from: &str, to: &str) -> Result<Vec<i32>, StoreError> {
+        if from == to {
+            return Ok(Vec::new());
+        }
+
+        let moved: Vec<Entry> = self.internal.entries
+            .values()
+            .filter(|e| e.category == from)
+            .map(|e| Entry { category: to.to_owned(), ..e.clone() })
+            .collect();
+        let mut affected = self.put_all(moved)?;
+
+        for stashed in self.stash.values_mut() {
+            if stashed.entry.category == from {
+                affected.push(stashed.entry.id);
+                stashed.entry.category = to.to_owned();
+            }
+        }
+
+        affected.sort_unstable();
+        affected.dedup();
+        Ok(affected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn academic_years_start_in_september() {
+        let (from, to) = Lifecycle::academic_year(2017).unwrap();
+        assert_eq!(from, "2017-09-01".parse().unwrap());
+        assert_eq!(to, "2018-08-31".parse().unwrap());
+    }
+
+    #[test]
+    fn academic_years_out_of_range_are_none() {
+        assert!(Lifecycle::academic_year(i32::max_value()).is_none());
+    }
+
+    fn entry(creation: &str, disbandment: Option<&str>) -> Entry {
+        let disbandment = disbandment.map_or("null".to_owned(), |d| format!("{:?}", d));
+        serde_json::from_str(&format!(
+            r#"{{"id":1,"name":"","name_eng":"","category":"","tags":[],"desc":"","desc_eng":"","files":[],"icon":null,"creation":{:?},"disbandment":{}}}"#,
+            creation,
+            disbandment)).unwrap()
+    }
+
+    #[test]
+    fn lifecycle_skips_unreadable_dates() {
+        let founded = Lifecycle { founded_to: Some("2010-01-01".parse().unwrap()), ..Default::default() };
+        assert!(founded.matches(&entry("2005-09-01", None)));
+        assert!(!founded.matches(&entry("sometime", None)));
+
+        let active = Lifecycle { active_during: Lifecycle::academic_year(2017), ..Default::default() };
+        assert!(active.matches(&entry("2005-09-01", None)));
+        assert!(active.matches(&entry("2005-09-01", Some("2018-06-30"))));
+        assert!(!active.matches(&entry("2005-09-01", Some("2016-06-30"))));
+        assert!(!active.matches(&entry("2005-09-01", Some("long ago"))));
+
+        let disbanded = Lifecycle { disbanded_after: Some("2000-01-01".parse().unwrap()), ..Default::default() };
+        assert!(disbanded.matches(&entry("2005-09-01", Some("2016-06-30"))));
+        assert!(!disbanded.matches(&entry("2005-09-01", Some("long ago"))));
+        assert!(!disbanded.matches(&entry("2005-09-01", None)));
+    }
 }
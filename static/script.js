@@ -1,17 +1,12 @@
 const DEBOUNCE_TIMEOUT = 500;
-const CATEGORIES = [
-  '素拓',
-  '艺术',
-  '公益',
-  '体育',
-  '科创',
-  '文化',
-];
 const DATE_FORMAT = "YYYY-MM-DD";
 const FP_FORMAT = "Y-m-d";
 let CONFIG;
 
 let conn;
+let ready = false; // Connected and authenticated
+
+const CHUNK_SIZE = 256 * 1024;
 
 // Setup moment
 moment.updateLocale('en', {
@@ -33,7 +28,8 @@ moment.updateLocale('en', {
   }
 });
 
-function buildWsURI(key) {
+function buildWsURI() {
+  let path = '/';
   let host = CONFIG.ws.host;
   let port = ':' + CONFIG.ws.port;
   if(host === '0.0.0.0') { // Virtual interface
@@ -44,31 +40,37 @@ function buildWsURI(key) {
     port = location.port;
     if(port !== '') port = ':' + port;
 
-    key = CONFIG.proxied + '/' + key;
-  } else {
-    key = '/' + key;
+    path = CONFIG.proxied + '/';
   };
 
   if (location.protocol === 'https:') {
-      return `wss://${host}${port}${key}`;
+      return `wss://${host}${port}${path}`;
   } else {
-      return `ws://${host}${port}${key}`;
+      return `ws://${host}${port}${path}`;
   }
 }
 
 function sendWait(data, raw = false) {
   return new Promise((resolve, reject) => {
+    const socket = conn;
+    let onClose;
     let callback = msg => {
       let payload = JSON.parse(msg.data);
-      if(payload.cmd !== 'update') {
-        conn.removeEventListener('message', callback);
+      if(!('cmd' in payload)) { // Not an event
+        socket.removeEventListener('message', callback);
+        socket.removeEventListener('close', onClose);
         resolve(payload);
       }
     };
-    conn.addEventListener('message', callback);
+    onClose = () => {
+      socket.removeEventListener('message', callback);
+      reject(new Error('Connection closed'));
+    };
+    socket.addEventListener('message', callback);
+    socket.addEventListener('close', onClose);
 
     const compiled = raw ? data : JSON.stringify(data);
-    conn.send(compiled);
+    socket.send(compiled);
   });
 }
 
@@ -83,15 +85,63 @@ function readAsTA(file) {
   });
 }
 
-async function uploadFile(dt, entry) {
+function waitReady() {
+  return new Promise(resolve => {
+    const check = () => ready ? resolve() : setTimeout(check, 500);
+    check();
+  });
+}
+
+function toHex(buffer) {
+  return Array.from(new Uint8Array(buffer))
+    .map(b => b.toString(16).padStart(2, '0'))
+    .join('');
+}
+
+// Uploads in chunks, resuming from the acknowledged offset if the connection drops
+async function uploadFile(dt, entry, onProgress = () => {}) {
   const segs = dt.name.split('.');
   const ext = segs[segs.length-1];
 
-  const createResult = await sendWait({ cmd: 'upload', ext, entry });
-  if(!createResult.ok) return false;
-
   const content = await readAsTA(dt);
-  const uploadResult = await sendWait(content, true);
+  const sha256 = toHex(await crypto.subtle.digest('SHA-256', content));
+
+  const session = await sendWait({ cmd: 'uploadStart', ext, entry, size: dt.size, sha256, filename: dt.name });
+  if(!session.ok) {
+    alert(`Cannot upload ${dt.name}: ${session.desc}`);
+    return null;
+  }
+
+  let offset = 0;
+  while(offset < dt.size) {
+    try {
+      await waitReady();
+      conn.send(JSON.stringify({ cmd: 'uploadChunk', id: session.id, offset }));
+      const ack = await sendWait(content.slice(offset, offset + CHUNK_SIZE), true);
+      if(!ack.ok && ack.error !== 'InvalidOffset') {
+        alert(`Cannot upload ${dt.name}: ${ack.desc}`);
+        return null;
+      }
+      offset = ack.offset;
+      onProgress(offset / dt.size);
+    } catch(e) {
+      // Connection dropped, ask where to continue from
+      await waitReady();
+      const status = await sendWait({ cmd: 'uploadStatus', id: session.id });
+      if(!status.ok) {
+        alert(`Cannot resume uploading ${dt.name}: ${status.desc}`);
+        return null;
+      }
+      offset = status.offset;
+    }
+  }
+
+  const result = await sendWait({ cmd: 'uploadFinish', id: session.id });
+  if(!result.ok) {
+    alert(`Cannot upload ${dt.name}: ${result.desc}`);
+    return null;
+  }
+  return result.name;
 }
 
 function deepClone(a) {
@@ -168,6 +218,7 @@ const desc = {
     locked: false,
     entries: [],
     referenceEntries: [],
+    categories: [],
     engMode: [],
     fileStore: {},
     searchStr: '',
@@ -182,6 +233,7 @@ const desc = {
     tagFilter: '',
     dragging: 0,
     uploading: false,
+    uploadProgress: 0,
     pendingDeletion: null,
 
     ctrlDown: false,
@@ -196,15 +248,27 @@ const desc = {
   },
   methods: {
     connect() {
-      conn = new WebSocket(buildWsURI(this.authKey));
+      const credential = this.authKey;
+      const sep = credential.indexOf(':');
+      const auth = { cmd: 'auth', key: credential };
+      if(sep !== -1) { // May be name:password
+        auth.name = credential.substr(0, sep);
+        auth.password = credential.substr(sep + 1);
+      }
+
+      conn = new WebSocket(buildWsURI());
+      conn.onopen = () => conn.send(JSON.stringify(auth));
       let initHandler = async msg => {
         conn.removeEventListener('message', initHandler);
         try {
           const data = JSON.parse(msg.data);
           if(data.ok) {
             if('limited' in data) this.limited = data.limited;
+            // Sent along with requests for files, instead of in their URLs
+            document.cookie = `preview=${data.preview}; path=/files; max-age=86400; SameSite=Strict`;
 
             this.connectionDown = false;
+            ready = true;
             if(!this.connected)
               this.init();
             else
@@ -216,14 +280,29 @@ const desc = {
                 // Is update
 
                 let index = this.referenceEntries.findIndex(e => e.id === payload.id);
-                if(index === -1) { // New entry
-                  this.entries.shift(deepClone(payload.payload));
-                  this.referenceEntries.shift(payload.payload);
+                if(index === -1) { // New or restored entry, kept ordered by id
+                  const at = list => {
+                    const pos = list.findIndex(e => e.id > payload.id);
+                    return pos === -1 ? list.length : pos;
+                  };
+                  this.entries.splice(at(this.entries), 0, deepClone(payload.payload));
+                  this.referenceEntries.splice(at(this.referenceEntries), 0, payload.payload);
                 } else {
                   let ni = this.entries.findIndex(e => e.id === payload.id);
                   this.$set(this.entries, ni, deepClone(payload.payload));
                   this.$set(this.referenceEntries, index, payload.payload);
                 }
+              } else if(payload.cmd === 'delete') {
+                // Deleted or purged
+                this.entries = this.entries.filter(e => e.id !== payload.id);
+                this.referenceEntries = this.referenceEntries.filter(e => e.id !== payload.id);
+              } else if(payload.cmd === 'delivery') {
+                // Outcome of a sendKey
+                const d = payload.delivery;
+                if(d.ok)
+                  alert(`The login link has been delivered to ${d.to}.`);
+                else
+                  alert(`Cannot send the login link to ${d.to}: ${d.error}`);
               }
             });
           }
@@ -234,6 +313,7 @@ const desc = {
       conn.addEventListener('message', initHandler);
 
       conn.onclose = () => {
+        ready = false;
         if(!this.connected) return; // Wrong key
         this.connectionDown = true;
         setTimeout(() => {
@@ -273,21 +353,30 @@ const desc = {
 
         if(curPtr >= this.referenceEntries.length || this.referenceEntries[curPtr].id > e.id) {
           // New
-          await sendWait({ cmd: 'put', payload: e });
+          const resp = await sendWait({ cmd: 'put', payload: e });
+          if(!resp.ok) console.warn(`Entry #${e.id} rejected: ${resp.desc}`);
         } else {
           if(!deepEq(e, this.referenceEntries[curPtr])) {
             // Update
             const resp = await sendWait({ cmd: 'put', payload: e });
+            if(!resp.ok) {
+              console.warn(`Entry #${e.id} rejected: ${resp.desc}`);
+              ++curPtr;
+              continue;
+            }
+
             const data = resp.payload;
             const target = this.entries.find(i => i.id === e.id)
 
             if(target) {
               target.type = data.type;
               target.timestamp = data.timestamp;
+              target.author = data.author;
             }
 
             e.type = data.type;
             e.timestamp = data.timestamp;
+            e.author = data.author;
           }
           ++curPtr;
         }
@@ -321,6 +410,7 @@ const desc = {
         icon: null,
         creation: moment().format(DATE_FORMAT),
         disbandment: null,
+        contact: null,
       });
 
       setTimeout(() => {
@@ -435,7 +525,10 @@ const desc = {
 
       for(const f of list)
         if(f.type.indexOf('image/') === 0) {
-          await uploadFile(f, id);
+          this.uploadProgress = 0;
+          const name = await uploadFile(f, id, p => this.uploadProgress = p);
+          // Added to the entry being edited, which is then submitted with put
+          if(name !== null) this.insertFile(name);
           --this.uploading;
         }
       // Upload finished, refresh list
@@ -470,8 +563,33 @@ const desc = {
       this.activeFile.files.push(file);
     },
 
+    moveFile(entry, index, offset) {
+      const target = index + offset;
+      if(target < 0 || target >= entry.files.length) return;
+
+      // Reordered locally, and submitted together with other unsaved edits
+      const [moved] = entry.files.splice(index, 1);
+      entry.files.splice(target, 0, moved);
+
+      // Swap references in desc
+      const swapRefs = segs => segs.map((seg, i) => {
+        const frontEmpty = i === 0 || segs[i-1] === '';
+        const backEmpty = i === segs.length-1 || segs[i+1] === '';
+        const result = frontEmpty && backEmpty && seg.match(/^<(\d+)>$/);
+        if(!result) return seg;
+        const id = parseInt(result[1], 10);
+        if(id === index+1) return `<${target+1}>`;
+        if(id === target+1) return `<${index+1}>`;
+        return seg;
+      });
+
+      entry.desc = swapRefs(entry.desc.split('\n')).join('\n');
+      entry.desc_eng = swapRefs(entry.desc_eng.split('\n')).join('\n');
+    },
+
     removeFile(entry, index) {
       const files = entry.files.splice(index, 1);
+      if(entry.captions) Vue.delete(entry.captions, files[0]);
 
       // Empty the icon if needed
       if(entry.icon === files[0])
@@ -530,10 +648,22 @@ const desc = {
 
     async genKey(entry) {
       // TODO: disable this when using as an individual
-      const result = await sendWait({ cmd: 'genKey', target: entry.id });
+      const label = prompt("Who is this key for? (e.g. President, leave empty for none)");
+      if(label === null) return;
+      const result = await sendWait({ cmd: 'genKey', target: entry.id, label });
       prompt("The world has stopped now. Copy the key and let me forget it for good.", result.key);
     },
 
+    async sendKey(entry) {
+      const label = prompt("Who is this key for? (e.g. President, leave empty for none)");
+      if(label === null) return;
+      const result = await sendWait({ cmd: 'sendKey', target: entry.id, label });
+      if(result.ok)
+        alert(`A login link is being sent to ${result.delivery.to}. You will be told once it is delivered.`);
+      else
+        alert(`Cannot send the login link: ${result.desc || result.error}`);
+    },
+
     disband(entry) {
       entry.disbandment = moment().format(DATE_FORMAT); // ISO 8601
     },
@@ -549,8 +679,8 @@ const desc = {
       fp.open();
     },
 
-    storeUri(uri) {
-      return '/store/' + uri;
+    storeUri(uri, size = 'thumb') {
+      return `/files/${uri}?size=${size}`;
     },
 
     discardAll() {
@@ -616,7 +746,7 @@ const desc = {
       if(this.files === null) return null;
       // Filter present tags
       if(this.activeFile)
-        return this.files.filter(f => !this.activeFile.files.includes(f));
+        return this.files.filter(f => !this.activeFile.files.includes(f.name));
       else return this.files;
     },
 
@@ -687,6 +817,22 @@ async function setup() {
   // Bootstrap app
   const app = new Vue(desc);
 
+  const cateResp = await fetch('/query/categories');
+  app.categories = await cateResp.json();
+
+  // One-time login link
+  if(location.hash.startsWith('#redeem=')) {
+    const token = location.hash.substr('#redeem='.length);
+    history.replaceState(null, '', location.pathname);
+    const redeemResp = await fetch(`/redeem/${encodeURIComponent(token)}`, { method: 'POST' });
+    if(redeemResp.ok) {
+      app.authKey = (await redeemResp.json()).key;
+      app.connect();
+    } else {
+      alert('This login link has expired or has already been used.');
+    }
+  }
+
   // Update timestamp
   setInterval(() => {
     app.currentTime = Date.now();